@@ -0,0 +1,224 @@
+use crate::changes::ChangeStream;
+use crate::codec::{RawCodec, ValueCodec};
+use crate::entry;
+use crate::error::Result;
+use crate::key_directory::KeyMetadata;
+use crate::kv_store::KVStore;
+use crate::version::Version;
+use std::io;
+use std::sync::Arc;
+
+/// An async facade over `KVStore` for tokio applications.
+///
+/// Reads and writes go to `std::fs` files, so each call that touches disk runs on tokio's
+/// blocking thread pool instead of the executor thread awaiting it. Queries that are answered
+/// from the in-memory key directory stay synchronous.
+///
+/// ```
+/// use bitcask::{AsyncKVStore, KVStore};
+///
+/// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+/// let dir = tempfile::tempdir().unwrap();
+/// let store = KVStore::<String>::new(dir.path().to_str().unwrap().to_string(), 1024).unwrap();
+/// let store = AsyncKVStore::new(store);
+///
+/// store.put("name".to_string(), b"bitcask".to_vec()).await.unwrap();
+/// assert_eq!(store.get("name".to_string()).await.unwrap(), Some(b"bitcask".to_vec()));
+/// # });
+/// ```
+pub struct AsyncKVStore<T: entry::key::Serializable, V = Vec<u8>, C: ValueCodec<V> = RawCodec> {
+    store: Arc<KVStore<T, V, C>>,
+}
+
+impl<T: entry::key::Serializable, V, C: ValueCodec<V>> Clone for AsyncKVStore<T, V, C> {
+    fn clone(&self) -> Self {
+        AsyncKVStore {
+            store: self.store.clone(),
+        }
+    }
+}
+
+impl<T, V, C> AsyncKVStore<T, V, C>
+where
+    T: entry::key::Serializable + 'static,
+    V: Send + 'static,
+    C: ValueCodec<V> + 'static,
+{
+    pub fn new(store: KVStore<T, V, C>) -> AsyncKVStore<T, V, C> {
+        AsyncKVStore {
+            store: Arc::new(store),
+        }
+    }
+
+    pub async fn get(&self, key: T) -> Result<Option<V>> {
+        self.run_blocking(move |store| store.get(key)).await
+    }
+
+    pub async fn put(&self, key: T, value: V) -> Result<()> {
+        self.run_blocking(move |store| store.put(key, value)).await
+    }
+
+    pub async fn put_with_version(&self, key: T, value: V) -> Result<Version> {
+        self.run_blocking(move |store| store.put_with_version(key, value))
+            .await
+    }
+
+    pub async fn delete(&self, key: T) -> Result<()> {
+        self.run_blocking(move |store| store.delete(key)).await
+    }
+
+    pub async fn delete_with_version(&self, key: T) -> Result<Version> {
+        self.run_blocking(move |store| store.delete_with_version(key))
+            .await
+    }
+
+    pub async fn get_with_version(&self, key: T) -> Result<Option<(V, Version)>> {
+        self.run_blocking(move |store| store.get_with_version(key))
+            .await
+    }
+
+    pub async fn put_if_absent(&self, key: T, value: V) -> Result<Option<Version>> {
+        self.run_blocking(move |store| store.put_if_absent(key, value))
+            .await
+    }
+
+    pub async fn compare_and_swap(
+        &self,
+        key: T,
+        expected_version: Version,
+        value: V,
+    ) -> Result<Option<Version>> {
+        self.run_blocking(move |store| store.compare_and_swap(key, expected_version, value))
+            .await
+    }
+
+    pub async fn delete_if_version(&self, key: T, expected_version: Version) -> Result<bool> {
+        self.run_blocking(move |store| store.delete_if_version(key, expected_version))
+            .await
+    }
+
+    /// Opens a `ChangeStream` from `position`, see `KVStore::changes_from`. Reading the
+    /// stream blocks, so consumers drive it from a blocking task.
+    pub async fn changes_from(&self, position: Version) -> Result<ChangeStream<T>> {
+        self.run_blocking(move |store| store.changes_from(position))
+            .await
+    }
+
+    pub fn contains(&self, key: T) -> bool {
+        self.store.contains(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.store.is_empty()
+    }
+
+    pub fn stat(&self, key: T) -> Option<KeyMetadata> {
+        self.store.stat(key)
+    }
+
+    /// The wrapped store, for calls that have no async counterpart. Anything on it that
+    /// touches disk blocks the calling thread.
+    pub fn blocking(&self) -> &Arc<KVStore<T, V, C>> {
+        &self.store
+    }
+
+    // a panic inside the store is resumed on the awaiting task, just as it would have
+    // surfaced from a direct call.
+    async fn run_blocking<R, F>(&self, operation: F) -> Result<R>
+    where
+        F: FnOnce(&KVStore<T, V, C>) -> Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let store = self.store.clone();
+
+        match tokio::task::spawn_blocking(move || operation(&store)).await {
+            Ok(result) => result,
+            Err(error) if error.is_panic() => std::panic::resume_unwind(error.into_panic()),
+            Err(error) => Err(io::Error::other(error).into()),
+        }
+    }
+}
+
+impl<T: entry::key::Serializable + 'static> AsyncKVStore<T, Vec<u8>, RawCodec> {
+    pub async fn incr_by(&self, key: T, delta: i64) -> Result<i64> {
+        self.run_blocking(move |store| store.incr_by(key, delta))
+            .await
+    }
+
+    pub async fn incr_by_with_version(&self, key: T, delta: i64) -> Result<(i64, Version)> {
+        self.run_blocking(move |store| store.incr_by_with_version(key, delta))
+            .await
+    }
+
+    pub async fn decr_by(&self, key: T, delta: i64) -> Result<i64> {
+        self.run_blocking(move |store| store.decr_by(key, delta))
+            .await
+    }
+
+    pub async fn append(&self, key: T, suffix: Vec<u8>) -> Result<usize> {
+        self.run_blocking(move |store| store.append(key, &suffix))
+            .await
+    }
+
+    pub async fn append_with_version(&self, key: T, suffix: Vec<u8>) -> Result<(usize, Version)> {
+        self.run_blocking(move |store| store.append_with_version(key, &suffix))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::async_kv_store::AsyncKVStore;
+    use crate::kv_store::KVStore;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_put_get_delete() {
+        let dir = tempdir().unwrap();
+        let store = AsyncKVStore::new(
+            KVStore::<String>::new(dir.path().to_str().unwrap().to_string(), 1024).unwrap(),
+        );
+
+        store.put("key1".to_string(), vec![1, 2, 3]).await.unwrap();
+        assert_eq!(
+            store.get("key1".to_string()).await.unwrap(),
+            Some(vec![1, 2, 3])
+        );
+        assert!(store.contains("key1".to_string()));
+
+        store.delete("key1".to_string()).await.unwrap();
+        assert_eq!(store.get("key1".to_string()).await.unwrap(), None);
+        assert!(store.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_concurrent_tasks() {
+        let dir = tempdir().unwrap();
+        let store = AsyncKVStore::new(
+            KVStore::<String>::new(dir.path().to_str().unwrap().to_string(), 256).unwrap(),
+        );
+
+        let handles: Vec<_> = (0..16u8)
+            .map(|task| {
+                let store = store.clone();
+                tokio::spawn(async move {
+                    for i in 0..10u8 {
+                        let key = format!("key-{}-{}", task, i);
+                        store.put(key.clone(), vec![task, i]).await.unwrap();
+                        assert_eq!(store.get(key).await.unwrap(), Some(vec![task, i]));
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        assert_eq!(store.len(), 16 * 10);
+    }
+}
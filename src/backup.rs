@@ -0,0 +1,118 @@
+use crate::error::{BitcaskError, Result};
+use crate::options::KVStoreOptions;
+use crate::segment::Segment;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const MANIFEST_FILE_NAME: &str = "MANIFEST";
+
+/// What `KVStore::backup` put into the target directory. It is also written there as
+/// `MANIFEST`, which the store itself ignores when the backup is opened.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BackupManifest {
+    /// Seconds since the Unix epoch at which the active segment was sealed for the backup.
+    pub created_at: u64,
+    /// The segments in the backup, oldest first.
+    pub segments: Vec<BackupSegment>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BackupSegment {
+    pub file_id: u64,
+    pub size: u64,
+}
+
+impl BackupManifest {
+    /// Reads the manifest of a backup directory.
+    pub fn read(directory: impl AsRef<Path>) -> Result<BackupManifest> {
+        let bytes = fs::read(directory.as_ref().join(MANIFEST_FILE_NAME))?;
+
+        Ok(serde_json::from_slice(&bytes).map_err(io::Error::from)?)
+    }
+}
+
+// sealed segments are never written again, so a hard link is as good as a copy and costs
+// nothing; files on another filesystem are copied instead.
+pub(crate) fn write_backup(segments: &[Arc<Segment>], target: &Path) -> Result<BackupManifest> {
+    if target.exists() && KVStoreOptions::contains_segments(target)? {
+        return Err(BitcaskError::AlreadyExists(target.display().to_string()));
+    }
+
+    fs::create_dir_all(target)?;
+
+    let mut manifest = BackupManifest {
+        created_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+        segments: Vec::with_capacity(segments.len()),
+    };
+
+    for segment in segments {
+        let source = PathBuf::from(&segment.file_path);
+        let destination = target.join(source.file_name().unwrap());
+
+        if fs::hard_link(&source, &destination).is_err() {
+            fs::copy(&source, &destination)?;
+            File::open(&destination)?.sync_all()?;
+        }
+
+        manifest.segments.push(BackupSegment {
+            file_id: segment.file_id,
+            size: segment.store.len()?,
+        });
+    }
+
+    // the manifest goes in last and in one rename, so a backup with a manifest is complete.
+    let staged = target.join(format!("{}.tmp", MANIFEST_FILE_NAME));
+    fs::write(
+        &staged,
+        serde_json::to_vec_pretty(&manifest).map_err(io::Error::from)?,
+    )?;
+    File::open(&staged)?.sync_all()?;
+    fs::rename(&staged, target.join(MANIFEST_FILE_NAME))?;
+
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backup::BackupManifest;
+    use crate::error::BitcaskError;
+    use crate::kv_store::KVStore;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_backup_opens_as_a_store() {
+        let dir = tempdir().unwrap();
+        let backup_dir = tempdir().unwrap();
+        let backup_path = backup_dir.path().join("backup");
+        let kv_store =
+            KVStore::<String>::new(dir.path().to_str().unwrap().to_string(), 30).unwrap();
+
+        kv_store.put("key1".to_string(), vec![1; 20]).unwrap();
+        kv_store.put("key2".to_string(), vec![2; 20]).unwrap();
+        kv_store.put("key1".to_string(), vec![3]).unwrap();
+
+        let manifest = kv_store.backup(&backup_path).unwrap();
+        kv_store.put("key3".to_string(), vec![4]).unwrap();
+        kv_store.delete("key2".to_string()).unwrap();
+
+        assert_eq!(manifest.segments.len(), 3);
+        assert_eq!(BackupManifest::read(&backup_path).unwrap(), manifest);
+
+        let backup = KVStore::<String>::new(backup_path.to_str().unwrap().to_string(), 30).unwrap();
+        assert_eq!(backup.get("key1".to_string()).unwrap(), Some(vec![3]));
+        assert_eq!(backup.get("key2".to_string()).unwrap(), Some(vec![2; 20]));
+        assert_eq!(backup.get("key3".to_string()).unwrap(), None);
+
+        assert!(matches!(
+            kv_store.backup(&backup_path),
+            Err(BitcaskError::AlreadyExists(_))
+        ));
+    }
+}
@@ -0,0 +1,52 @@
+use bitcask::{RestorePoint, Version, restore};
+use chrono::DateTime;
+use std::env;
+use std::process;
+
+const USAGE: &str = "Usage: restore <source-dir> <target-dir> (--at <unix-seconds|RFC 3339> | --until <file_id:offset>)";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let (source, target, point) = match parse_args(&args) {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+
+    match restore(source, target, point) {
+        Ok(keys) => println!("Restored {} keys into {}", keys, target),
+        Err(e) => {
+            eprintln!("Error {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<(&str, &str, RestorePoint), String> {
+    match args {
+        [source, target, flag, point] if flag == "--at" => Ok((
+            source,
+            target,
+            RestorePoint::Timestamp(parse_timestamp(point)?),
+        )),
+        [source, target, flag, point] if flag == "--until" => {
+            let version = point.parse::<Version>().map_err(|e| e.to_string())?;
+            Ok((source, target, RestorePoint::Version(version)))
+        }
+        _ => Err("Expected a source, a target and a restore point".to_string()),
+    }
+}
+
+fn parse_timestamp(point: &str) -> Result<u32, String> {
+    if let Ok(seconds) = point.parse::<u32>() {
+        return Ok(seconds);
+    }
+
+    let time = DateTime::parse_from_rfc3339(point)
+        .map_err(|e| format!("Invalid timestamp {}: {}", point, e))?;
+
+    u32::try_from(time.timestamp()).map_err(|_| format!("Timestamp {} is out of range", point))
+}
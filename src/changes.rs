@@ -0,0 +1,266 @@
+use crate::entry;
+use crate::error::Result;
+use crate::segments::{SegmentPin, Segments};
+use crate::version::Version;
+use crate::watch::Watcher;
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A put or tombstone read from the log by a `ChangeStream`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChangeEvent<T> {
+    pub key: T,
+    /// The bytes written by the store's codec, `None` for a delete.
+    pub value: Option<Vec<u8>>,
+    /// Seconds since the Unix epoch at which the entry was written.
+    pub timestamp: u32,
+    /// Where the entry starts in the log, the same as the version it was written with.
+    pub position: Version,
+    /// Where the next entry starts. Checkpoint this and pass it to `KVStore::changes_from`
+    /// to resume right after this event.
+    pub next_position: Version,
+    /// Set on every entry of a transaction but its last, which are all handed out together.
+    pub continues_group: bool,
+}
+
+/// Every entry appended to a store from a log position on, in log order, following the log
+/// across segment rollovers. Returned by `KVStore::changes_from`.
+///
+/// Entries of a transaction are only handed out once the whole group is in the log. The
+/// segment the stream is reading is pinned, which stops compaction from removing it or any
+/// newer segment; live entries that compaction moves past the stream's position show up
+/// again as puts. Iterating blocks until the next entry is written and ends when the store
+/// is dropped.
+pub struct ChangeStream<T: entry::key::Serializable> {
+    segments: Arc<Segments>,
+    // where reading continues, at or past the `next_position` of everything in `pending`.
+    cursor: Version,
+    position: Version,
+    pending: VecDeque<ChangeEvent<T>>,
+    pin: SegmentPin,
+    // used only to wake up when something is written.
+    wakeup: Watcher<T>,
+}
+
+impl<T: entry::key::Serializable> ChangeStream<T> {
+    // `pin` already holds `position.file_id`, which the caller has checked is on disk.
+    pub(crate) fn new(
+        segments: Arc<Segments>,
+        position: Version,
+        pin: SegmentPin,
+        wakeup: Watcher<T>,
+    ) -> ChangeStream<T> {
+        ChangeStream {
+            segments,
+            cursor: position,
+            position,
+            pending: VecDeque::new(),
+            pin,
+            wakeup,
+        }
+    }
+
+    /// The position right after the last event returned.
+    pub fn position(&self) -> Version {
+        self.position
+    }
+
+    /// The next event if one has been written, without waiting.
+    pub fn try_next(&mut self) -> Result<Option<ChangeEvent<T>>> {
+        if self.pending.is_empty() {
+            self.fill()?;
+        }
+
+        let event = self.pending.pop_front();
+
+        if let Some(event) = &event {
+            self.position = event.next_position;
+        }
+
+        Ok(event)
+    }
+
+    /// Waits up to `timeout` for the next event.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Result<Option<ChangeEvent<T>>> {
+        if let Some(event) = self.try_next()? {
+            return Ok(Some(event));
+        }
+
+        if self.wakeup.recv_timeout(timeout).is_none() {
+            return Ok(None);
+        }
+
+        while self.wakeup.try_recv().is_some() {}
+
+        self.try_next()
+    }
+
+    // reads what has been appended past the cursor. A segment is only left behind once a
+    // newer one exists, since until then the writer may still append to it.
+    fn fill(&mut self) -> Result<()> {
+        loop {
+            let file_id = self.cursor.file_id;
+            let segment = self.segments.segment(file_id)?;
+            let next_file_id = self
+                .segments
+                .file_ids()
+                .into_iter()
+                .find(|id| *id > file_id);
+            let mut group = Vec::new();
+
+            for (entry, offset, length) in
+                segment.read_from::<T>(self.cursor.offset as u32, next_file_id.is_none())?
+            {
+                let continues_group = entry.value.continues_group();
+                let next_position = Version {
+                    file_id,
+                    offset: (offset + length) as u64,
+                };
+                group.push(ChangeEvent {
+                    value: (!entry.value.is_tombstone()).then_some(entry.value.value),
+                    key: entry.key,
+                    timestamp: entry.timestamp,
+                    position: Version {
+                        file_id,
+                        offset: offset as u64,
+                    },
+                    next_position,
+                    continues_group,
+                });
+
+                if continues_group {
+                    continue;
+                }
+
+                self.cursor = next_position;
+                self.pending.extend(group.drain(..));
+            }
+
+            if !self.pending.is_empty() {
+                return Ok(());
+            }
+
+            // what is left of a sealed segment is a group cut short by a crash, if anything.
+            let Some(next_file_id) = next_file_id else {
+                return Ok(());
+            };
+
+            self.pin = self.segments.pin(BTreeSet::from([next_file_id]));
+            self.cursor = Version {
+                file_id: next_file_id,
+                offset: 0,
+            };
+        }
+    }
+}
+
+impl<T: entry::key::Serializable> Iterator for ChangeStream<T> {
+    type Item = Result<ChangeEvent<T>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.try_next() {
+                Ok(Some(event)) => return Some(Ok(event)),
+                Ok(None) => {}
+                Err(error) => return Some(Err(error)),
+            }
+
+            self.wakeup.recv()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kv_store::KVStore;
+    use crate::version::Version;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    const START: Version = Version {
+        file_id: 0,
+        offset: 0,
+    };
+
+    #[test]
+    fn test_stream_follows_rollovers_and_resumes() {
+        let dir = tempdir().unwrap();
+        let kv_store =
+            KVStore::<String>::new(dir.path().to_str().unwrap().to_string(), 30).unwrap();
+
+        kv_store.put("key1".to_string(), vec![1; 20]).unwrap();
+        kv_store.put("key2".to_string(), vec![2; 20]).unwrap();
+        kv_store.delete("key1".to_string()).unwrap();
+
+        let mut changes = kv_store.changes_from(START).unwrap();
+        let first = changes.try_next().unwrap().unwrap();
+        assert_eq!(first.key, "key1");
+        assert_eq!(first.value, Some(vec![1; 20]));
+        assert_eq!(changes.position(), first.next_position);
+
+        let mut transaction = kv_store.transaction();
+        transaction.put("key3".to_string(), vec![3]).unwrap();
+        transaction.put("key4".to_string(), vec![4]).unwrap();
+        transaction.commit().unwrap();
+
+        let mut resumed = kv_store.changes_from(first.next_position).unwrap();
+        let mut events = Vec::new();
+        while let Some(event) = resumed.try_next().unwrap() {
+            events.push(event);
+        }
+
+        assert_eq!(
+            events
+                .iter()
+                .map(|event| (event.key.as_str(), event.value.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("key2", Some(vec![2; 20])),
+                ("key1", None),
+                ("key3", Some(vec![3])),
+                ("key4", Some(vec![4])),
+            ]
+        );
+        let last = events.last().unwrap();
+        assert_eq!(
+            last.position,
+            kv_store.stat("key4".to_string()).unwrap().version
+        );
+        assert_eq!(resumed.position(), last.next_position);
+    }
+
+    #[test]
+    fn test_stream_waits_for_new_writes() {
+        let dir = tempdir().unwrap();
+        let kv_store = Arc::new(
+            KVStore::<String>::new(dir.path().to_str().unwrap().to_string(), 1024).unwrap(),
+        );
+        let mut changes = kv_store.changes_from(START).unwrap();
+
+        assert!(
+            changes
+                .next_timeout(Duration::from_millis(10))
+                .unwrap()
+                .is_none()
+        );
+
+        let writer = {
+            let kv_store = kv_store.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                kv_store.put("key1".to_string(), vec![1]).unwrap();
+            })
+        };
+
+        let event = changes.next().unwrap().unwrap();
+        assert_eq!(event.key, "key1");
+        assert_eq!(
+            event.position,
+            kv_store.stat("key1".to_string()).unwrap().version
+        );
+        writer.join().unwrap();
+    }
+}
@@ -0,0 +1,116 @@
+use crate::error::{BitcaskError, Result};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+/// Turns values of type `V` into the bytes stored in a segment and back.
+///
+/// A store picks its codec through its type, e.g. `KVStore<String, User, JsonCodec>`.
+/// Bytes that don't decode are reported as `BitcaskError::InvalidValue`.
+pub trait ValueCodec<V> {
+    fn encode(value: V) -> Result<Vec<u8>>;
+    fn decode(bytes: Vec<u8>) -> Result<V>;
+}
+
+/// Stores `Vec<u8>` values as they are; the default codec.
+pub struct RawCodec;
+
+impl ValueCodec<Vec<u8>> for RawCodec {
+    fn encode(value: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(value)
+    }
+
+    fn decode(bytes: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(bytes)
+    }
+}
+
+/// Stores `String` values as their UTF-8 bytes.
+pub struct Utf8Codec;
+
+impl ValueCodec<String> for Utf8Codec {
+    fn encode(value: String) -> Result<Vec<u8>> {
+        Ok(value.into_bytes())
+    }
+
+    fn decode(bytes: Vec<u8>) -> Result<String> {
+        String::from_utf8(bytes).map_err(|error| BitcaskError::InvalidValue(error.to_string()))
+    }
+}
+
+/// Stores any serde type as JSON, which keeps values readable with the plain `GET` command.
+pub struct JsonCodec;
+
+impl<V: Serialize + DeserializeOwned> ValueCodec<V> for JsonCodec {
+    fn encode(value: V) -> Result<Vec<u8>> {
+        serde_json::to_vec(&value).map_err(|error| BitcaskError::InvalidValue(error.to_string()))
+    }
+
+    fn decode(bytes: Vec<u8>) -> Result<V> {
+        serde_json::from_slice(&bytes)
+            .map_err(|error| BitcaskError::InvalidValue(error.to_string()))
+    }
+}
+
+/// Stores any serde type in postcard's compact binary format. Field names are not written,
+/// so values are smaller than JSON but only decode into the struct layout they were written
+/// with.
+pub struct BinaryCodec;
+
+impl<V: Serialize + DeserializeOwned> ValueCodec<V> for BinaryCodec {
+    fn encode(value: V) -> Result<Vec<u8>> {
+        postcard::to_allocvec(&value).map_err(|error| BitcaskError::InvalidValue(error.to_string()))
+    }
+
+    fn decode(bytes: Vec<u8>) -> Result<V> {
+        postcard::from_bytes(&bytes).map_err(|error| BitcaskError::InvalidValue(error.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codec::{BinaryCodec, JsonCodec, Utf8Codec, ValueCodec};
+    use crate::error::BitcaskError;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        age: u32,
+    }
+
+    fn user() -> User {
+        User {
+            name: "ada".to_string(),
+            age: 36,
+        }
+    }
+
+    #[test]
+    fn test_serde_codecs_round_trip() {
+        let json = JsonCodec::encode(user()).unwrap();
+        assert_eq!(json, br#"{"name":"ada","age":36}"#.to_vec());
+        assert_eq!(
+            <JsonCodec as ValueCodec<User>>::decode(json).unwrap(),
+            user()
+        );
+
+        let binary = BinaryCodec::encode(user()).unwrap();
+        assert!(binary.len() < 10);
+        assert_eq!(
+            <BinaryCodec as ValueCodec<User>>::decode(binary).unwrap(),
+            user()
+        );
+    }
+
+    #[test]
+    fn test_undecodable_bytes_are_invalid_values() {
+        assert!(matches!(
+            Utf8Codec::decode(vec![0xff]),
+            Err(BitcaskError::InvalidValue(_))
+        ));
+        assert!(matches!(
+            <JsonCodec as ValueCodec<User>>::decode(b"{".to_vec()),
+            Err(BitcaskError::InvalidValue(_))
+        ));
+    }
+}
@@ -1,9 +1,313 @@
-use std::fmt::{Debug, Display};
+use crate::error::{BitcaskError, Result};
+use std::fmt::Debug;
 use std::hash::Hash;
+use uuid::Uuid;
 
-pub trait Serializable: Eq + Hash + Clone + Display + Debug {
-    fn serialize(&self) -> Result<Vec<u8>, std::io::Error>;
-    fn deserialize(bytes: Vec<u8>) -> Result<Self, std::io::Error>
+// Keys are compared through their `Ord` in the key directory and through their serialized
+// bytes anywhere they are handled as raw bytes, so every implementation below encodes keys
+// such that comparing the bytes gives the same order as comparing the keys.
+pub trait Serializable: Eq + Ord + Hash + Clone + Debug + Send + Sync {
+    fn serialize(&self) -> Result<Vec<u8>>;
+    fn deserialize(bytes: Vec<u8>) -> Result<Self>
     where
         Self: Sized;
 }
+
+/// A key type that can also be one element of a tuple key.
+///
+/// Variable-length components are escaped and terminated so that the element boundaries
+/// survive concatenation and a shorter element still sorts before any longer element that
+/// extends it: `("a", 2)` comes before `("ab", 1)`.
+pub trait KeyComponent: Serializable {
+    fn serialize_component(&self, out: &mut Vec<u8>);
+    // returns the component at the start of `bytes` along with how many bytes it took.
+    fn deserialize_component(bytes: &[u8]) -> Result<(Self, usize)>
+    where
+        Self: Sized;
+}
+
+impl Serializable for String {
+    fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(self.as_bytes().to_vec())
+    }
+    fn deserialize(bytes: Vec<u8>) -> Result<String> {
+        String::from_utf8(bytes).map_err(|error| BitcaskError::InvalidKey(error.to_string()))
+    }
+}
+
+impl Serializable for Vec<u8> {
+    fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(self.clone())
+    }
+    fn deserialize(bytes: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(bytes)
+    }
+}
+
+impl Serializable for Uuid {
+    fn serialize(&self) -> Result<Vec<u8>> {
+        Ok(self.as_bytes().to_vec())
+    }
+    fn deserialize(bytes: Vec<u8>) -> Result<Uuid> {
+        Uuid::from_slice(&bytes).map_err(|error| BitcaskError::InvalidKey(error.to_string()))
+    }
+}
+
+// Inside a component a zero byte is written as 0x00 0xFF and the component ends with
+// 0x00 0x01, which sorts below every escaped byte that could follow in a longer component.
+const ESCAPE: u8 = 0x00;
+const ESCAPED_ZERO: u8 = 0xFF;
+const TERMINATOR: u8 = 0x01;
+
+fn write_escaped(bytes: &[u8], out: &mut Vec<u8>) {
+    for byte in bytes {
+        out.push(*byte);
+
+        if *byte == ESCAPE {
+            out.push(ESCAPED_ZERO);
+        }
+    }
+
+    out.extend_from_slice(&[ESCAPE, TERMINATOR]);
+}
+
+fn read_escaped(bytes: &[u8]) -> Result<(Vec<u8>, usize)> {
+    let mut unescaped = Vec::new();
+    let mut position = 0;
+
+    while position < bytes.len() {
+        let byte = bytes[position];
+
+        if byte != ESCAPE {
+            unescaped.push(byte);
+            position += 1;
+            continue;
+        }
+
+        match bytes.get(position + 1) {
+            Some(&ESCAPED_ZERO) => unescaped.push(ESCAPE),
+            Some(&TERMINATOR) => return Ok((unescaped, position + 2)),
+            _ => break,
+        }
+
+        position += 2;
+    }
+
+    Err(BitcaskError::InvalidKey(
+        "key component is not terminated".to_string(),
+    ))
+}
+
+impl KeyComponent for String {
+    fn serialize_component(&self, out: &mut Vec<u8>) {
+        write_escaped(self.as_bytes(), out);
+    }
+    fn deserialize_component(bytes: &[u8]) -> Result<(String, usize)> {
+        let (unescaped, length) = read_escaped(bytes)?;
+
+        Ok((String::deserialize(unescaped)?, length))
+    }
+}
+
+impl KeyComponent for Vec<u8> {
+    fn serialize_component(&self, out: &mut Vec<u8>) {
+        write_escaped(self, out);
+    }
+    fn deserialize_component(bytes: &[u8]) -> Result<(Vec<u8>, usize)> {
+        read_escaped(bytes)
+    }
+}
+
+impl KeyComponent for Uuid {
+    fn serialize_component(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.as_bytes());
+    }
+    fn deserialize_component(bytes: &[u8]) -> Result<(Uuid, usize)> {
+        let length = size_of::<Uuid>();
+        let bytes = bytes
+            .get(..length)
+            .ok_or_else(|| BitcaskError::InvalidKey("uuid component is too short".to_string()))?;
+
+        Ok((Uuid::deserialize(bytes.to_vec())?, length))
+    }
+}
+
+fn fixed_width<const N: usize>(bytes: &[u8]) -> Result<[u8; N]> {
+    bytes
+        .get(..N)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| BitcaskError::InvalidKey(format!("expected {} bytes for an integer key", N)))
+}
+
+// unsigned integers are written big-endian so the most significant byte is compared first.
+macro_rules! unsigned_key {
+    ($($type:ty),*) => {$(
+        impl Serializable for $type {
+            fn serialize(&self) -> Result<Vec<u8>> {
+                Ok(self.to_be_bytes().to_vec())
+            }
+            fn deserialize(bytes: Vec<u8>) -> Result<$type> {
+                if bytes.len() != size_of::<$type>() {
+                    return Err(BitcaskError::InvalidKey(format!(
+                        "expected {} bytes for a {} key, found {}",
+                        size_of::<$type>(),
+                        stringify!($type),
+                        bytes.len()
+                    )));
+                }
+
+                Ok(<$type>::from_be_bytes(fixed_width(&bytes)?))
+            }
+        }
+
+        impl KeyComponent for $type {
+            fn serialize_component(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_be_bytes());
+            }
+            fn deserialize_component(bytes: &[u8]) -> Result<($type, usize)> {
+                Ok((<$type>::from_be_bytes(fixed_width(bytes)?), size_of::<$type>()))
+            }
+        }
+    )*};
+}
+
+// signed integers flip the sign bit on top of that, which moves negative numbers below
+// positive ones while keeping each half in order.
+macro_rules! signed_key {
+    ($($type:ty => $unsigned:ty),*) => {$(
+        impl Serializable for $type {
+            fn serialize(&self) -> Result<Vec<u8>> {
+                let flipped = (*self as $unsigned) ^ (1 << (<$unsigned>::BITS - 1));
+                flipped.serialize()
+            }
+            fn deserialize(bytes: Vec<u8>) -> Result<$type> {
+                let flipped = <$unsigned>::deserialize(bytes)?;
+                Ok((flipped ^ (1 << (<$unsigned>::BITS - 1))) as $type)
+            }
+        }
+
+        impl KeyComponent for $type {
+            fn serialize_component(&self, out: &mut Vec<u8>) {
+                let flipped = (*self as $unsigned) ^ (1 << (<$unsigned>::BITS - 1));
+                flipped.serialize_component(out);
+            }
+            fn deserialize_component(bytes: &[u8]) -> Result<($type, usize)> {
+                let (flipped, length) = <$unsigned>::deserialize_component(bytes)?;
+                Ok(((flipped ^ (1 << (<$unsigned>::BITS - 1))) as $type, length))
+            }
+        }
+    )*};
+}
+
+unsigned_key!(u8, u16, u32, u64, u128);
+signed_key!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+// a tuple is its components one after the other, so tuples sort by their first component,
+// then by the second, and so on.
+macro_rules! tuple_key {
+    ($($name:ident),+) => {
+        impl<$($name: KeyComponent),+> Serializable for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn serialize(&self) -> Result<Vec<u8>> {
+                let ($($name,)+) = self;
+                let mut out = Vec::new();
+                $($name.serialize_component(&mut out);)+
+
+                Ok(out)
+            }
+            fn deserialize(bytes: Vec<u8>) -> Result<Self> {
+                let mut position = 0;
+                let key = ($({
+                    let (component, length) = $name::deserialize_component(&bytes[position..])?;
+                    position += length;
+                    component
+                },)+);
+
+                if position != bytes.len() {
+                    return Err(BitcaskError::InvalidKey(format!(
+                        "{} trailing bytes after tuple key",
+                        bytes.len() - position
+                    )));
+                }
+
+                Ok(key)
+            }
+        }
+    };
+}
+
+tuple_key!(A, B);
+tuple_key!(A, B, C);
+tuple_key!(A, B, C, D);
+
+#[cfg(test)]
+mod tests {
+    use crate::entry::key::Serializable;
+    use crate::error::BitcaskError;
+    use uuid::Uuid;
+
+    fn assert_order_preserved<T: Serializable>(mut keys: Vec<T>) {
+        keys.sort();
+        let encoded: Vec<Vec<u8>> = keys.iter().map(|key| key.serialize().unwrap()).collect();
+
+        let mut sorted = encoded.clone();
+        sorted.sort();
+        assert_eq!(
+            encoded, sorted,
+            "encoding does not preserve the order of {:?}",
+            keys
+        );
+
+        for (key, bytes) in keys.iter().zip(encoded) {
+            assert_eq!(&T::deserialize(bytes).unwrap(), key);
+        }
+    }
+
+    #[test]
+    fn test_string_deserialize_rejects_invalid_utf8() {
+        let result = String::deserialize(vec![0xff, 0xfe]);
+
+        assert!(matches!(result, Err(BitcaskError::InvalidKey(_))));
+    }
+
+    #[test]
+    fn test_integer_keys_preserve_order() {
+        assert_order_preserved(vec![0u64, 1, 255, 256, u64::MAX]);
+        assert_order_preserved(vec![i64::MIN, -256, -1, 0, 1, 255, i64::MAX]);
+        assert_order_preserved(vec![i8::MIN, -1, 0, i8::MAX]);
+        assert!(u32::deserialize(vec![1, 2]).is_err());
+    }
+
+    #[test]
+    fn test_byte_and_uuid_keys_preserve_order() {
+        assert_order_preserved(vec![vec![], vec![0], vec![0, 0], vec![1], vec![255]]);
+        assert_order_preserved(vec![Uuid::nil(), Uuid::from_u128(1), Uuid::max()]);
+    }
+
+    #[test]
+    fn test_tuple_keys_preserve_order() {
+        assert_order_preserved(vec![
+            ("a".to_string(), 2u64),
+            ("a".to_string(), 10),
+            ("ab".to_string(), 1),
+            ("a\0b".to_string(), 1),
+            ("b".to_string(), 0),
+            (String::new(), u64::MAX),
+        ]);
+        assert_order_preserved(vec![
+            (vec![0u8], -1i32, Uuid::nil()),
+            (vec![0u8], -1, Uuid::max()),
+            (vec![0u8, 0], i32::MIN, Uuid::nil()),
+            (vec![], 5, Uuid::nil()),
+        ]);
+    }
+
+    #[test]
+    fn test_tuple_deserialize_rejects_malformed_bytes() {
+        let mut bytes = (7u32, "tenant".to_string()).serialize().unwrap();
+        bytes.push(1);
+        assert!(<(u32, String)>::deserialize(bytes).is_err());
+
+        assert!(<(String, u32)>::deserialize(b"no terminator".to_vec()).is_err());
+    }
+}
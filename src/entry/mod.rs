@@ -1,17 +1,57 @@
 use std::time::{SystemTime, UNIX_EPOCH};
 pub mod key;
 
+use crate::error::BitcaskError;
 use crate::util;
 use key::Serializable;
+use std::io::Error;
+use std::io::ErrorKind::InvalidData;
 use std::mem;
 
 const TOMBSTONE_MARKER_SIZE: usize = mem::size_of::<u8>();
 const RESERVED_LENGTH_FOR_KEY_SIZE: usize = mem::size_of::<u32>();
 const RESERVED_LENGTH_FOR_VALUE_SIZE: usize = mem::size_of::<u32>();
 const RESERVED_TIMESTAMP_SIZE: usize = mem::size_of::<u32>();
+
+// bits of the marker byte written after the value. Entries written before transactions
+// only ever used 0 and 1, so they read back as single entries.
+const TOMBSTONE_FLAG: u8 = 0b01;
+// set on every entry of an atomic group except the last one, so replay can tell a group
+// cut short by a crash from a complete one.
+const GROUP_CONTINUES_FLAG: u8 = 0b10;
+
 pub struct ValueReference {
     pub(crate) value: Vec<u8>,
-    tombstone: u8,
+    flags: u8,
+}
+
+impl ValueReference {
+    pub fn is_tombstone(&self) -> bool {
+        self.flags & TOMBSTONE_FLAG != 0
+    }
+
+    pub fn continues_group(&self) -> bool {
+        self.flags & GROUP_CONTINUES_FLAG != 0
+    }
+}
+
+// true when the entry starting at `offset` runs past the end of `content`, either because
+// the header itself is cut short or because the sizes it declares reach beyond the last byte.
+pub(crate) fn is_truncated(content: &[u8], offset: u32) -> bool {
+    let header_size =
+        RESERVED_TIMESTAMP_SIZE + RESERVED_LENGTH_FOR_KEY_SIZE + RESERVED_LENGTH_FOR_VALUE_SIZE;
+    let key_size_offset = offset + RESERVED_TIMESTAMP_SIZE as u32;
+    let value_size_offset = key_size_offset + RESERVED_LENGTH_FOR_KEY_SIZE as u32;
+
+    match (
+        util::get_int_from_le_bytes(content, key_size_offset),
+        util::get_int_from_le_bytes(content, value_size_offset),
+    ) {
+        (Ok(key_size), Ok(value_size)) => {
+            offset as usize + header_size + key_size as usize + value_size as usize > content.len()
+        }
+        _ => true,
+    }
 }
 
 pub struct Entry<T: Serializable> {
@@ -24,10 +64,7 @@ impl<T: Serializable> Entry<T> {
     pub fn new(key: T, value: Vec<u8>) -> Entry<T> {
         Entry {
             key,
-            value: ValueReference {
-                value,
-                tombstone: 0,
-            },
+            value: ValueReference { value, flags: 0 },
             timestamp: 0,
         }
     }
@@ -35,10 +72,7 @@ impl<T: Serializable> Entry<T> {
     pub fn new_preserving_timestamp(key: T, value: Vec<u8>, timestamp: u32) -> Entry<T> {
         Entry {
             key,
-            value: ValueReference {
-                value,
-                tombstone: 0,
-            },
+            value: ValueReference { value, flags: 0 },
             timestamp,
         }
     }
@@ -48,17 +82,48 @@ impl<T: Serializable> Entry<T> {
             key,
             value: ValueReference {
                 value: Vec::new(),
-                tombstone: 1,
+                flags: TOMBSTONE_FLAG,
             },
             timestamp: 0,
         }
     }
 
-    pub fn encode(&mut self) -> Result<Vec<u8>, std::io::Error> {
+    pub fn new_deleted_entry_preserving_timestamp(key: T, timestamp: u32) -> Entry<T> {
+        Entry {
+            key,
+            value: ValueReference {
+                value: Vec::new(),
+                flags: TOMBSTONE_FLAG,
+            },
+            timestamp,
+        }
+    }
+
+    // marks the entry as followed by another entry of the same atomic group.
+    pub(crate) fn continue_group(&mut self) {
+        self.value.flags |= GROUP_CONTINUES_FLAG;
+    }
+
+    pub fn encode(&mut self) -> Result<Vec<u8>, BitcaskError> {
         let serialized_key = self.key.serialize()?;
         let key_size = serialized_key.len();
         let value_size = self.value.value.len() + TOMBSTONE_MARKER_SIZE;
 
+        if key_size > u32::MAX as usize {
+            return Err(BitcaskError::InvalidKey(format!(
+                "key {:?} is {} bytes serialized, more than the size field holds",
+                self.key, key_size
+            )));
+        }
+
+        if value_size > u32::MAX as usize {
+            return Err(BitcaskError::InvalidValue(format!(
+                "value of {:?} is {} bytes, more than the size field holds",
+                self.key,
+                self.value.value.len()
+            )));
+        }
+
         let mut encoded = Vec::with_capacity(
             RESERVED_TIMESTAMP_SIZE
                 + RESERVED_LENGTH_FOR_KEY_SIZE
@@ -67,32 +132,20 @@ impl<T: Serializable> Entry<T> {
                 + value_size,
         );
 
-        println!(
-            "{} {}",
-            "total size is: ",
-            RESERVED_TIMESTAMP_SIZE
-                + RESERVED_LENGTH_FOR_KEY_SIZE
-                + RESERVED_LENGTH_FOR_VALUE_SIZE
-                + key_size
-                + value_size
-        );
-
-        let mut timestamp = self.timestamp;
-
         if self.timestamp == 0 {
-            timestamp = SystemTime::now()
+            self.timestamp = SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_secs() as u32;
         }
 
-        encoded.extend_from_slice(&timestamp.to_le_bytes());
+        encoded.extend_from_slice(&self.timestamp.to_le_bytes());
         encoded.extend_from_slice(&(key_size as u32).to_le_bytes());
         encoded.extend_from_slice(&(value_size as u32).to_le_bytes());
         encoded.extend_from_slice(&serialized_key);
 
         encoded.extend_from_slice(&self.value.value);
-        encoded.push(self.value.tombstone);
+        encoded.push(self.value.flags);
 
         Ok(encoded)
     }
@@ -105,55 +158,53 @@ impl<T: Serializable> Entry<T> {
     //	└───────────┴──────────┴────────────┴─────┴───────┘
 
     pub fn decode(content: Vec<u8>, offset: u32) -> Result<Entry<T>, std::io::Error> {
-        let (entry, _) = Self::decode_from(content, offset)?;
+        let (entry, _) = Self::decode_from(&content, offset)?;
 
         Ok(entry)
     }
 
-    pub fn decode_multi(content: Vec<u8>) -> Result<Vec<(Entry<T>, u32, u32)>, std::io::Error> {
-        let length = content.len();
-        let mut offset: u32 = 0;
-        let mut entries = Vec::new();
-
-        while offset < length as u32 {
-            let (entry, traversed_offset) = Self::decode_from(content.clone(), offset)?;
-            entries.push((entry, offset, traversed_offset - offset + 1));
-            offset = traversed_offset + 1;
-        }
-
-        Ok(entries)
-    }
-
-    fn decode_from(content: Vec<u8>, offset: u32) -> Result<(Entry<T>, u32), std::io::Error> {
+    // returns the entry starting at `offset` along with the offset of its last byte.
+    // Truncated or malformed content is reported as InvalidData rather than a panic.
+    pub(crate) fn decode_from(content: &[u8], offset: u32) -> Result<(Entry<T>, u32), Error> {
         let mut updated_offset = offset;
-        let timestamp = util::get_int_from_le_bytes(&content, updated_offset)?;
+        let timestamp = util::get_int_from_le_bytes(content, updated_offset)?;
         updated_offset += RESERVED_TIMESTAMP_SIZE as u32;
-        let key_size = util::get_int_from_le_bytes(&content, updated_offset)?;
+        let key_size = util::get_int_from_le_bytes(content, updated_offset)?;
         updated_offset += RESERVED_LENGTH_FOR_KEY_SIZE as u32;
-        let value_size = util::get_int_from_le_bytes(&content, updated_offset)?;
+        let value_size = util::get_int_from_le_bytes(content, updated_offset)?;
         updated_offset += RESERVED_LENGTH_FOR_VALUE_SIZE as u32;
 
-        let key = content[updated_offset as usize..(updated_offset + key_size) as usize].to_vec();
+        if (value_size as usize) < TOMBSTONE_MARKER_SIZE {
+            return Err(Error::new(
+                InvalidData,
+                "value size is missing the tombstone",
+            ));
+        }
 
-        updated_offset += key_size;
+        let key_start = updated_offset as usize;
+        let value_start = key_start + key_size as usize;
+        let entry_end = value_start + value_size as usize;
 
-        let value = content[updated_offset as usize
-            ..(updated_offset + value_size) as usize - TOMBSTONE_MARKER_SIZE]
-            .to_vec();
+        if entry_end > content.len() {
+            return Err(Error::new(
+                InvalidData,
+                "found bytes are less than expected",
+            ));
+        }
 
-        updated_offset += value_size - TOMBSTONE_MARKER_SIZE as u32;
+        let key = content[key_start..value_start].to_vec();
+        let value = content[value_start..entry_end - TOMBSTONE_MARKER_SIZE].to_vec();
+        let flags = content[entry_end - TOMBSTONE_MARKER_SIZE];
 
-        let tombstone =
-            &content[updated_offset as usize..updated_offset as usize + TOMBSTONE_MARKER_SIZE];
+        updated_offset = (entry_end - TOMBSTONE_MARKER_SIZE) as u32;
 
-        let value_reference = ValueReference {
-            value,
-            tombstone: tombstone[0],
-        };
+        let value_reference = ValueReference { value, flags };
+        let key =
+            T::deserialize(key).map_err(|error| Error::new(InvalidData, error.to_string()))?;
 
         Ok((
             Entry {
-                key: T::deserialize(key)?,
+                key,
                 value: value_reference,
                 timestamp,
             },
@@ -164,7 +215,7 @@ impl<T: Serializable> Entry<T> {
 
 #[cfg(test)]
 mod tests {
-    use crate::entry::Entry;
+    use crate::entry::{Entry, is_truncated};
     use std::time::{SystemTime, UNIX_EPOCH};
 
     // impl Serializable for String {
@@ -188,7 +239,7 @@ mod tests {
 
         assert_eq!(decoded_entry.key, key);
         assert_eq!(decoded_entry.value.value, value);
-        assert_eq!(decoded_entry.value.tombstone, entry.value.tombstone);
+        assert_eq!(decoded_entry.value.flags, entry.value.flags);
     }
 
     #[test]
@@ -206,7 +257,7 @@ mod tests {
 
         assert_eq!(decoded_entry.key, key);
         assert_eq!(decoded_entry.value.value, value);
-        assert_eq!(decoded_entry.value.tombstone, entry.value.tombstone);
+        assert_eq!(decoded_entry.value.flags, entry.value.flags);
         assert_eq!(decoded_entry.timestamp, entry.timestamp);
     }
 
@@ -219,7 +270,45 @@ mod tests {
         let decoded_entry = Entry::<String>::decode(encoded, 0).unwrap();
 
         assert_eq!(decoded_entry.key, key);
-        assert_eq!(decoded_entry.value.tombstone, entry.value.tombstone);
+        assert_eq!(decoded_entry.value.flags, entry.value.flags);
+    }
+
+    #[test]
+    fn test_group_flag_survives_encoding() {
+        let mut entry = Entry::new_deleted_entry("key".to_string());
+        entry.continue_group();
+        let decoded_entry = Entry::<String>::decode(entry.encode().unwrap(), 0).unwrap();
+
+        assert!(decoded_entry.value.is_tombstone());
+        assert!(decoded_entry.value.continues_group());
+
+        let mut entry = Entry::new("key".to_string(), vec![1]);
+        let decoded_entry = Entry::<String>::decode(entry.encode().unwrap(), 0).unwrap();
+
+        assert!(!decoded_entry.value.is_tombstone());
+        assert!(!decoded_entry.value.continues_group());
+    }
+
+    #[test]
+    fn test_decode_rejects_sizes_past_the_end() {
+        let mut content = Vec::new();
+        content.extend_from_slice(&1u32.to_le_bytes());
+        content.extend_from_slice(&u32::MAX.to_le_bytes());
+        content.extend_from_slice(&1u32.to_le_bytes());
+        content.extend_from_slice(b"key");
+
+        let result = Entry::<String>::decode(content, 0);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_is_truncated() {
+        let mut entry = Entry::new("key".to_string(), vec![1, 2, 3]);
+        let encoded = entry.encode().unwrap();
+
+        assert!(!is_truncated(&encoded, 0));
+        assert!(is_truncated(&encoded[..encoded.len() - 1], 0));
+        assert!(is_truncated(&encoded[..6], 0));
     }
 
     #[test]
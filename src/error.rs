@@ -0,0 +1,75 @@
+use std::fmt::{Display, Formatter};
+
+pub type Result<T> = std::result::Result<T, BitcaskError>;
+
+#[derive(Debug)]
+pub enum BitcaskError {
+    /// A directory, index or other named resource does not exist.
+    NotFound(String),
+    /// The entry at `offset` in segment `file_id` could not be decoded.
+    Corruption {
+        file_id: u64,
+        offset: u64,
+    },
+    Io(std::io::Error),
+    /// A key could not be serialized or deserialized.
+    InvalidKey(String),
+    /// Another writer holds the lock on the store directory.
+    Locked(String),
+    /// A write was attempted on a store opened read-only.
+    ReadOnly,
+    /// The options passed to `KVStoreOptions::open` are inconsistent.
+    InvalidOptions(String),
+    /// `error_if_exists` was set and the directory already holds a store.
+    AlreadyExists(String),
+    /// A version string could not be parsed.
+    InvalidVersion(String),
+    /// The stored value does not fit the operation, e.g. incrementing a non-integer.
+    InvalidValue(String),
+    /// A transaction was not committed because a key it read has changed since.
+    Conflict(String),
+    /// A follower could not serve a read as fresh as was asked for.
+    Stale(String),
+}
+
+impl Display for BitcaskError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BitcaskError::NotFound(what) => write!(f, "{} not found", what),
+            BitcaskError::Corruption { file_id, offset } => write!(
+                f,
+                "corrupted entry in segment {} at offset {}",
+                file_id, offset
+            ),
+            BitcaskError::Io(error) => write!(f, "io error: {}", error),
+            BitcaskError::InvalidKey(reason) => write!(f, "invalid key: {}", reason),
+            BitcaskError::Locked(directory) => {
+                write!(f, "directory {} is locked by another writer", directory)
+            }
+            BitcaskError::ReadOnly => write!(f, "store is opened read-only"),
+            BitcaskError::InvalidOptions(reason) => write!(f, "invalid options: {}", reason),
+            BitcaskError::AlreadyExists(directory) => {
+                write!(f, "directory {} already contains a store", directory)
+            }
+            BitcaskError::InvalidVersion(version) => write!(f, "invalid version: {}", version),
+            BitcaskError::InvalidValue(reason) => write!(f, "invalid value: {}", reason),
+            BitcaskError::Conflict(reason) => write!(f, "transaction conflict: {}", reason),
+            BitcaskError::Stale(reason) => write!(f, "replica too stale: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for BitcaskError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BitcaskError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for BitcaskError {
+    fn from(error: std::io::Error) -> Self {
+        BitcaskError::Io(error)
+    }
+}
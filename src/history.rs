@@ -0,0 +1,213 @@
+use crate::entry;
+use crate::error::Result;
+use crate::segment::AppendEntryResponse;
+use crate::version::Version;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// One state a key has been in, as returned by `KVStore::history`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Revision<V> {
+    /// `None` where the key was deleted.
+    pub value: Option<V>,
+    /// Seconds since the Unix epoch at which the entry was written.
+    pub timestamp: u32,
+    pub version: Version,
+}
+
+#[derive(Clone, Copy)]
+pub struct Link {
+    pub location: AppendEntryResponse,
+    pub deleted: bool,
+}
+
+// For every key, each of its entries that is still on disk and may answer a read inside the
+// retention window, in log order, which is oldest first. Writers record entries while they
+// hold the key's directory shard, so a key's chain grows in the same order as its log;
+// compaction only ever moves a chain's last entry and drops whole segments, which keeps that
+// order intact. With no retention nothing is recorded at all.
+pub struct VersionChains<T: entry::key::Serializable> {
+    chains: Mutex<HashMap<T, Vec<Link>>>,
+    retention: Duration,
+}
+
+impl<T: entry::key::Serializable> VersionChains<T> {
+    pub fn new(retention: Duration) -> VersionChains<T> {
+        VersionChains {
+            chains: Mutex::new(HashMap::new()),
+            retention,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.retention.is_zero()
+    }
+
+    // the oldest second the retention window still covers.
+    pub fn cutoff(&self) -> u32 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        now.saturating_sub(self.retention.as_secs()) as u32
+    }
+
+    // also forgets the key's entries that were superseded before the window, which no read
+    // inside it can land on, and the whole chain once all that is left is an old tombstone.
+    pub fn record(&self, key: &T, location: &AppendEntryResponse, deleted: bool) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let link = Link {
+            location: *location,
+            deleted,
+        };
+        let cutoff = self.cutoff();
+        let mut chains = self.chains.lock().unwrap();
+        let chain = chains.entry(key.clone()).or_default();
+        chain.push(link);
+
+        let superseded = chain
+            .windows(2)
+            .take_while(|pair| pair[1].location.timestamp <= cutoff)
+            .count();
+        chain.drain(..superseded);
+
+        if let [last] = chain.as_slice()
+            && last.deleted
+            && last.location.timestamp <= cutoff
+        {
+            chains.remove(key);
+        }
+    }
+
+    // `while_locked` runs before the chain can lose entries to compaction, so segments
+    // pinned there still hold every entry returned.
+    pub fn chain<R>(&self, key: &T, while_locked: impl FnOnce(&[Link]) -> R) -> (Vec<Link>, R) {
+        let chains = self.chains.lock().unwrap();
+        let chain = chains.get(key).cloned().unwrap_or_default();
+        let result = while_locked(&chain);
+
+        (chain, result)
+    }
+
+    // when the entry at `version` was superseded by the key's next entry, `None` while it is
+    // still the latest one or no longer part of the chain.
+    pub fn superseded_at(&self, key: &T, version: Version) -> Option<u32> {
+        let chains = self.chains.lock().unwrap();
+        let chain = chains.get(key)?;
+        let position = chain
+            .iter()
+            .position(|link| link.location.version() == version)?;
+
+        chain.get(position + 1).map(|link| link.location.timestamp)
+    }
+
+    pub fn relocate(&self, key: &T, from: Version, to: &AppendEntryResponse) {
+        let mut chains = self.chains.lock().unwrap();
+
+        if let Some(link) = chains.get_mut(key).and_then(|chain| {
+            chain
+                .iter_mut()
+                .find(|link| link.location.version() == from)
+        }) {
+            link.location = *to;
+        }
+    }
+
+    // runs `remove_segment` with the chains locked and forgets the segment's entries if it
+    // was removed, so no reader can look up an entry of a file that is already gone.
+    pub fn remove_file(
+        &self,
+        file_id: u64,
+        remove_segment: impl FnOnce() -> Result<bool>,
+    ) -> Result<bool> {
+        let mut chains = self.chains.lock().unwrap();
+
+        if !remove_segment()? {
+            return Ok(false);
+        }
+
+        chains.retain(|_, chain| {
+            chain.retain(|link| link.location.file_id != file_id);
+            !chain.is_empty()
+        });
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::history::VersionChains;
+    use crate::segment::AppendEntryResponse;
+    use std::time::Duration;
+
+    fn location(file_id: u64, offset: i64, timestamp: u32) -> AppendEntryResponse {
+        AppendEntryResponse {
+            file_id,
+            offset,
+            entry_length: 10,
+            value_size: 1,
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn test_chain_follows_relocation_and_removal() {
+        let chains = VersionChains::<String>::new(Duration::MAX);
+        let key = "key".to_string();
+        chains.record(&key, &location(1, 0, 100), false);
+        chains.record(&key, &location(1, 10, 200), true);
+        chains.record(&key, &location(2, 0, 300), false);
+
+        assert_eq!(
+            chains.superseded_at(&key, location(1, 0, 0).version()),
+            Some(200)
+        );
+        assert_eq!(
+            chains.superseded_at(&key, location(2, 0, 0).version()),
+            None
+        );
+
+        chains.relocate(&key, location(2, 0, 0).version(), &location(3, 0, 300));
+        chains.remove_file(1, || Ok(true)).unwrap();
+        chains.remove_file(2, || Ok(true)).unwrap();
+
+        let (chain, _) = chains.chain(&key, |_| ());
+        assert_eq!(chain.len(), 1);
+        assert_eq!(chain[0].location.file_id, 3);
+        assert_eq!(chain[0].location.timestamp, 300);
+    }
+
+    #[test]
+    fn test_record_keeps_only_the_retention_window() {
+        let key = "key".to_string();
+        let disabled = VersionChains::<String>::new(Duration::ZERO);
+        disabled.record(&key, &location(1, 0, 100), false);
+        assert!(disabled.chain(&key, |_| ()).0.is_empty());
+
+        let chains = VersionChains::<String>::new(Duration::from_secs(3600));
+        let now = chains.cutoff() + 3600;
+        chains.record(&key, &location(1, 0, now - 7200), false);
+        chains.record(&key, &location(1, 10, now - 5000), false);
+        chains.record(&key, &location(1, 20, now - 10), false);
+
+        let (chain, _) = chains.chain(&key, |_| ());
+        assert_eq!(
+            chain
+                .iter()
+                .map(|link| link.location.offset)
+                .collect::<Vec<_>>(),
+            vec![10, 20]
+        );
+
+        let deleted = "deleted".to_string();
+        chains.record(&deleted, &location(1, 30, now - 7200), false);
+        chains.record(&deleted, &location(1, 40, now - 5000), true);
+        assert!(chains.chain(&deleted, |_| ()).0.is_empty());
+    }
+}
@@ -1,27 +1,216 @@
 use crate::entry;
+use crate::options::KeyDirectoryBackend;
 use crate::segment::AppendEntryResponse;
-use std::collections::HashMap;
+use crate::version::Version;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::{BuildHasher, RandomState};
+use std::ops::RangeBounds;
+use std::sync::{RwLock, RwLockWriteGuard};
 
+const DEFAULT_SHARD_COUNT: usize = 16;
+
+/// What the directory knows about a key without touching its value on disk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyMetadata {
+    pub value_size: u32,
+    pub timestamp: u32,
+    pub file_id: u64,
+    pub version: Version,
+}
+
+pub enum Shard<T: entry::key::Serializable> {
+    Hash(HashMap<T, AppendEntryResponse>),
+    Ordered(BTreeMap<T, AppendEntryResponse>),
+}
+
+impl<T: entry::key::Serializable> Shard<T> {
+    pub fn insert(&mut self, key: T, value: AppendEntryResponse) -> Option<AppendEntryResponse> {
+        match self {
+            Shard::Hash(entries) => entries.insert(key, value),
+            Shard::Ordered(entries) => entries.insert(key, value),
+        }
+    }
+
+    pub fn get(&self, key: &T) -> Option<&AppendEntryResponse> {
+        match self {
+            Shard::Hash(entries) => entries.get(key),
+            Shard::Ordered(entries) => entries.get(key),
+        }
+    }
+
+    pub fn remove(&mut self, key: &T) -> Option<AppendEntryResponse> {
+        match self {
+            Shard::Hash(entries) => entries.remove(key),
+            Shard::Ordered(entries) => entries.remove(key),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Shard::Hash(entries) => entries.len(),
+            Shard::Ordered(entries) => entries.len(),
+        }
+    }
+
+    pub fn entries(&self) -> Vec<(T, AppendEntryResponse)> {
+        match self {
+            Shard::Hash(entries) => entries.iter().map(|(k, v)| (k.clone(), *v)).collect(),
+            Shard::Ordered(entries) => entries.iter().map(|(k, v)| (k.clone(), *v)).collect(),
+        }
+    }
+
+    pub fn range(&self, range: &impl RangeBounds<T>) -> Vec<(T, AppendEntryResponse)> {
+        match self {
+            Shard::Hash(entries) => entries
+                .iter()
+                .filter(|(k, _)| range.contains(k))
+                .map(|(k, v)| (k.clone(), *v))
+                .collect(),
+            Shard::Ordered(entries) => entries
+                .range((range.start_bound(), range.end_bound()))
+                .map(|(k, v)| (k.clone(), *v))
+                .collect(),
+        }
+    }
+}
+
+// keys are spread over independently locked shards so writers touching different keys
+// don't contend with each other or with readers. The ordered backend keeps a single
+// shard so entries come out in key order.
 pub struct KeyDirectory<T: entry::key::Serializable> {
-    entry_by_key: HashMap<T, AppendEntryResponse>,
+    shards: Vec<RwLock<Shard<T>>>,
+    hasher: RandomState,
 }
 
 impl<T: entry::key::Serializable> KeyDirectory<T> {
-    pub fn new() -> KeyDirectory<T> {
+    pub fn new(backend: KeyDirectoryBackend) -> KeyDirectory<T> {
+        let shards = match backend {
+            KeyDirectoryBackend::Hash => (0..DEFAULT_SHARD_COUNT)
+                .map(|_| RwLock::new(Shard::Hash(HashMap::new())))
+                .collect(),
+            KeyDirectoryBackend::Ordered => vec![RwLock::new(Shard::Ordered(BTreeMap::new()))],
+        };
+
         KeyDirectory {
-            entry_by_key: HashMap::new(),
+            shards,
+            hasher: RandomState::new(),
+        }
+    }
+
+    pub fn put(&self, key: T, value: AppendEntryResponse) -> Option<AppendEntryResponse> {
+        self.write_shard(&key).insert(key, value)
+    }
+
+    pub fn get(&self, key: &T) -> Option<AppendEntryResponse> {
+        self.shard(key).read().unwrap().get(key).copied()
+    }
+
+    pub fn contains(&self, key: &T) -> bool {
+        self.shard(key).read().unwrap().get(key).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap().len())
+            .sum()
+    }
+
+    pub fn remove(&self, key: &T) -> Option<AppendEntryResponse> {
+        self.write_shard(key).remove(key)
+    }
+
+    // the write path holds the key's shard while appending, so entries for the same key
+    // reach the directory in the order they reached the log.
+    pub fn write_shard(&self, key: &T) -> RwLockWriteGuard<'_, Shard<T>> {
+        self.shard(key).write().unwrap()
+    }
+
+    // write-locks the shards of every key, always in shard order so two callers locking
+    // overlapping sets can't deadlock.
+    pub fn write_shards<'k>(&self, keys: impl IntoIterator<Item = &'k T>) -> LockedShards<'_, T>
+    where
+        T: 'k,
+    {
+        let indexes: BTreeSet<usize> = keys.into_iter().map(|key| self.shard_index(key)).collect();
+
+        LockedShards {
+            directory: self,
+            guards: indexes
+                .into_iter()
+                .map(|index| (index, self.shards[index].write().unwrap()))
+                .collect(),
         }
     }
 
-    pub fn put(&mut self, key: T, value: AppendEntryResponse) {
-        self.entry_by_key.insert(key, value);
+    pub fn entries(&self) -> Vec<(T, AppendEntryResponse)> {
+        self.shards
+            .iter()
+            .flat_map(|shard| shard.read().unwrap().entries())
+            .collect()
+    }
+
+    // entries with a key inside `range`, in key order whichever backend is used. Every shard
+    // is read-locked at once, so the result is a consistent cut with no write half applied,
+    // and `while_locked` runs before any writer can move on.
+    pub fn range<R>(
+        &self,
+        range: impl RangeBounds<T>,
+        while_locked: impl FnOnce(&[(T, AppendEntryResponse)]) -> R,
+    ) -> (Vec<(T, AppendEntryResponse)>, R) {
+        let shards: Vec<_> = self
+            .shards
+            .iter()
+            .map(|shard| shard.read().unwrap())
+            .collect();
+        let mut entries: Vec<(T, AppendEntryResponse)> = shards
+            .iter()
+            .flat_map(|shard| shard.range(&range))
+            .collect();
+
+        if shards.len() > 1 {
+            entries.sort_by(|(left, _), (right, _)| left.cmp(right));
+        }
+
+        let result = while_locked(&entries);
+
+        (entries, result)
+    }
+
+    fn shard(&self, key: &T) -> &RwLock<Shard<T>> {
+        &self.shards[self.shard_index(key)]
+    }
+
+    fn shard_index(&self, key: &T) -> usize {
+        self.hasher.hash_one(key) as usize % self.shards.len()
+    }
+}
+
+/// The shards of a set of keys, write-locked together by `KeyDirectory::write_shards`.
+/// Only keys passed to `write_shards` may be looked up.
+pub struct LockedShards<'a, T: entry::key::Serializable> {
+    directory: &'a KeyDirectory<T>,
+    guards: BTreeMap<usize, RwLockWriteGuard<'a, Shard<T>>>,
+}
+
+impl<T: entry::key::Serializable> LockedShards<'_, T> {
+    pub fn get(&self, key: &T) -> Option<&AppendEntryResponse> {
+        self.guards[&self.directory.shard_index(key)].get(key)
+    }
+
+    pub fn insert(&mut self, key: T, value: AppendEntryResponse) -> Option<AppendEntryResponse> {
+        let index = self.directory.shard_index(&key);
+        self.shard_mut(index).insert(key, value)
     }
 
-    pub fn get(&self, key: T) -> Option<&AppendEntryResponse> {
-        self.entry_by_key.get(&key)
+    pub fn remove(&mut self, key: &T) -> Option<AppendEntryResponse> {
+        let index = self.directory.shard_index(key);
+        self.shard_mut(index).remove(key)
     }
 
-    pub fn remove(&mut self, key: T) -> Option<AppendEntryResponse> {
-        self.entry_by_key.remove(&key)
+    fn shard_mut(&mut self, index: usize) -> &mut Shard<T> {
+        self.guards
+            .get_mut(&index)
+            .expect("key was not locked by write_shards")
     }
 }
@@ -1,73 +1,892 @@
+use crate::backup::{self, BackupManifest};
+use crate::changes::ChangeStream;
+use crate::codec::{RawCodec, ValueCodec};
 use crate::entry;
-use crate::key_directory::KeyDirectory;
-use crate::segment::AppendEntryResponse;
-use crate::segments::Segments;
-use std::sync::{Arc, RwLock};
+use crate::entry::Entry;
+use crate::error::{BitcaskError, Result};
+use crate::export::{self, ExportFormat, ExportRecord};
+use crate::history::{Link, Revision, VersionChains};
+use crate::key_directory::{KeyDirectory, KeyMetadata, LockedShards};
+use crate::kv_store_iter::KVStoreIter;
+use crate::options::KVStoreOptions;
+use crate::secondary_index::{IndexExtractor, SecondaryIndex};
+use crate::segment::{AppendEntryResponse, Segment};
+use crate::segment_stats::{SegmentStats, SegmentStatsTracker};
+use crate::segments::{SegmentPin, Segments};
+use crate::snapshot::Snapshot;
+use crate::transaction::Transaction;
+use crate::version::Version;
+use crate::watch::{WatchTarget, Watcher, Watchers};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{BufRead, Write};
+use std::marker::PhantomData;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
 
-pub struct KVStore<T: entry::key::Serializable> {
-    segments: Arc<RwLock<Segments>>,
+// every operation takes `&self`, so a store can be shared as `Arc<KVStore<T>>`.
+// Lock order on the write path is indexes, then the key's directory shard, then the
+// segments writer; readers only ever touch a single directory shard.
+pub struct KVStore<T: entry::key::Serializable, V = Vec<u8>, C: ValueCodec<V> = RawCodec> {
+    segments: Arc<Segments>,
     directory: KeyDirectory<T>,
+    indexes: RwLock<HashMap<String, Mutex<SecondaryIndex<T>>>>,
+    segment_stats: SegmentStatsTracker,
+    // segment file and offset up to which entries have been applied to the key directory.
+    replayed_up_to: Mutex<(u64, u32)>,
+    watchers: Watchers<T>,
+    history: VersionChains<T>,
+    // one compaction at a time.
+    compaction: Mutex<()>,
+    codec: PhantomData<fn() -> (V, C)>,
 }
 
-impl<T: entry::key::Serializable> KVStore<T> {
-    pub fn new(directory: String, max_segment_size: u32) -> Result<KVStore<T>, std::io::Error> {
-        let segments = Segments::new(directory, max_segment_size)?;
-        let directory = KeyDirectory::new();
+impl<T: entry::key::Serializable, V, C: ValueCodec<V>> KVStore<T, V, C> {
+    pub fn new(directory: String, max_segment_size: u32) -> Result<KVStore<T, V, C>> {
+        KVStoreOptions::new()
+            .max_segment_size(max_segment_size)
+            .open(directory)
+    }
+
+    pub(crate) fn open(directory: String, options: &KVStoreOptions) -> Result<KVStore<T, V, C>> {
+        let segments = if options.read_only {
+            Segments::open_read_only(directory, options.max_segment_size)?
+        } else {
+            Segments::new(directory, options.max_segment_size, options.sync_policy)?
+        };
+        let directory = KeyDirectory::new(options.key_directory_backend);
 
-        let mut kv_store = KVStore {
-            segments: Arc::new(RwLock::new(segments)),
+        let kv_store = KVStore {
+            segments: Arc::new(segments),
             directory,
+            indexes: RwLock::new(HashMap::new()),
+            segment_stats: SegmentStatsTracker::new(),
+            replayed_up_to: Mutex::new((0, 0)),
+            watchers: Watchers::new(options.watch_buffer_size),
+            history: VersionChains::new(options.history_retention),
+            compaction: Mutex::new(()),
+            codec: PhantomData,
         };
 
-        kv_store.reload().expect("Unable to reload kv store");
+        kv_store.reload()?;
 
         Ok(kv_store)
     }
 
-    pub fn put(&mut self, key: T, value: Vec<u8>) -> Result<(), std::io::Error> {
-        let mut segments = self.segments.write().unwrap();
-        let result = segments.append(key.clone(), value)?;
-        self.directory.put(key, result);
+    pub fn put(&self, key: T, value: V) -> Result<()> {
+        self.put_with_version(key, value)?;
+
+        Ok(())
+    }
+
+    /// Writes `value` and returns the version it was written at.
+    pub fn put_with_version(&self, key: T, value: V) -> Result<Version> {
+        let version = self.put_when(key, C::encode(value)?, None, |_| true)?;
+
+        Ok(version.expect("an unconditional write always happens"))
+    }
+
+    /// Writes `value` only if `key` has no live value. Returns the new version, or `None` if
+    /// the key was already present.
+    pub fn put_if_absent(&self, key: T, value: V) -> Result<Option<Version>> {
+        self.put_when(key, C::encode(value)?, None, |current| current.is_none())
+    }
+
+    /// Writes `value` only if the key's current version is `expected_version`. Returns the new
+    /// version, or `None` if the key was written or deleted since `expected_version` was read.
+    pub fn compare_and_swap(
+        &self,
+        key: T,
+        expected_version: Version,
+        value: V,
+    ) -> Result<Option<Version>> {
+        self.put_when(key, C::encode(value)?, None, |current| {
+            current.map(|current| current.version()) == Some(expected_version)
+        })
+    }
+
+    pub fn get(&self, key: T) -> Result<Option<V>> {
+        Ok(self.get_with_version(key)?.map(|(value, _)| value))
+    }
+
+    /// The key's value along with the version to pass to `compare_and_swap`.
+    pub fn get_with_version(&self, key: T) -> Result<Option<(V, Version)>> {
+        loop {
+            let Some(append_entry_response) = self.directory.get(&key) else {
+                return Ok(None);
+            };
+
+            match self.read_value(&append_entry_response) {
+                Ok(value) => return Ok(Some((C::decode(value)?, append_entry_response.version()))),
+                // compaction moved the entry and removed its segment after the lookup
+                Err(BitcaskError::NotFound(_))
+                    if self.directory.get(&key).map(|current| current.version())
+                        != Some(append_entry_response.version()) =>
+                {
+                    continue;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    pub fn delete(&self, key: T) -> Result<()> {
+        self.delete_with_version(key)?;
+
+        Ok(())
+    }
+
+    /// Deletes the key and returns the version its tombstone was written at.
+    pub fn delete_with_version(&self, key: T) -> Result<Version> {
+        let version = self.delete_when(key, |_| true)?;
+
+        Ok(version.expect("an unconditional delete always happens"))
+    }
+
+    /// Deletes the key only if its current version is `expected_version`. Returns whether the
+    /// key was deleted.
+    pub fn delete_if_version(&self, key: T, expected_version: Version) -> Result<bool> {
+        let version = self.delete_when(key, |current| {
+            current.map(|current| current.version()) == Some(expected_version)
+        })?;
+
+        Ok(version.is_some())
+    }
+
+    // the condition sees the key's current location while its shard is write-locked, so no
+    // other write to the key can slip in between the check and the append. Entries are
+    // stamped with the current time unless a `timestamp` is carried over from elsewhere.
+    fn put_when(
+        &self,
+        key: T,
+        value: Vec<u8>,
+        timestamp: Option<u32>,
+        condition: impl FnOnce(Option<&AppendEntryResponse>) -> bool,
+    ) -> Result<Option<Version>> {
+        let indexes = self.indexes.read().unwrap();
+        let terms_by_index: Vec<(&String, Vec<Vec<u8>>)> = indexes
+            .iter()
+            .map(|(name, index)| (name, index.lock().unwrap().terms(&value)))
+            .collect();
+
+        let mut shard = self.directory.write_shard(&key);
+
+        if !condition(shard.get(&key)) {
+            return Ok(None);
+        }
+
+        let watched_value = (!self.watchers.is_empty()).then(|| value.clone());
+        let result = match timestamp {
+            Some(timestamp) => {
+                self.segments
+                    .append_preserving_timestamp(key.clone(), value, timestamp)?
+            }
+            None => self.segments.append(key.clone(), value)?,
+        };
+        let previous = shard.insert(key.clone(), result);
+        self.segment_stats.record_put(&result, previous.as_ref());
+        self.history.record(&key, &result, false);
+
+        if let Some(value) = watched_value {
+            self.watchers.notify_put(&key, &value, result.version());
+        }
+
+        for (name, terms) in terms_by_index {
+            indexes[name].lock().unwrap().put(key.clone(), terms);
+        }
+
+        Ok(Some(result.version()))
+    }
+
+    // reads the current value and writes what `modify` makes of it with the key's shard
+    // write-locked throughout, so concurrent updates of one key are applied one at a time.
+    fn update(
+        &self,
+        key: T,
+        modify: impl FnOnce(Option<Vec<u8>>) -> Result<Vec<u8>>,
+    ) -> Result<(Vec<u8>, Version)> {
+        let indexes = self.indexes.read().unwrap();
+
+        let mut shard = self.directory.write_shard(&key);
+        let current = match shard.get(&key) {
+            Some(append_entry_response) => Some(self.read_value(append_entry_response)?),
+            None => None,
+        };
+        let value = modify(current)?;
+
+        let result = self.segments.append(key.clone(), value.clone())?;
+        let previous = shard.insert(key.clone(), result);
+        self.segment_stats.record_put(&result, previous.as_ref());
+        self.history.record(&key, &result, false);
+        self.watchers.notify_put(&key, &value, result.version());
+
+        for index in indexes.values() {
+            let mut index = index.lock().unwrap();
+            let terms = index.terms(&value);
+            index.put(key.clone(), terms);
+        }
+
+        Ok((value, result.version()))
+    }
+
+    fn read_value(&self, append_entry_response: &AppendEntryResponse) -> Result<Vec<u8>> {
+        let entry = self.segments.read::<T>(
+            append_entry_response.file_id,
+            append_entry_response.entry_length as usize,
+            append_entry_response.offset as u64,
+        )?;
+
+        Ok(entry.value.value)
+    }
+
+    fn delete_when(
+        &self,
+        key: T,
+        condition: impl FnOnce(Option<&AppendEntryResponse>) -> bool,
+    ) -> Result<Option<Version>> {
+        let indexes = self.indexes.read().unwrap();
+
+        let mut shard = self.directory.write_shard(&key);
+
+        if !condition(shard.get(&key)) {
+            return Ok(None);
+        }
+
+        let result = self.segments.append_delete(key.clone())?;
+        let previous = shard.remove(&key);
+        self.segment_stats.record_delete(&result, previous.as_ref());
+        self.history.record(&key, &result, true);
+        self.watchers.notify_delete(&key, result.version());
+
+        for index in indexes.values() {
+            index.lock().unwrap().remove(&key);
+        }
+
+        Ok(Some(result.version()))
+    }
+
+    /// Starts an optimistic transaction over several keys; see `Transaction`.
+    pub fn transaction(&self) -> Transaction<'_, T, V, C> {
+        Transaction::new(self)
+    }
+
+    // every key read or written stays locked from the version check until the group is in
+    // the log and applied, so no other write can land in between or see half of the group.
+    pub(crate) fn commit(
+        &self,
+        reads: BTreeMap<T, Option<Version>>,
+        writes: BTreeMap<T, Option<Vec<u8>>>,
+    ) -> Result<()> {
+        let indexes = self.indexes.read().unwrap();
+        let mut shards = self
+            .directory
+            .write_shards(reads.keys().chain(writes.keys()));
+
+        for (key, version) in &reads {
+            if shards.get(key).map(|current| current.version()) != *version {
+                return Err(BitcaskError::Conflict(format!(
+                    "{:?} changed since it was read",
+                    key
+                )));
+            }
+        }
+
+        let entries = writes
+            .into_iter()
+            .map(|(key, value)| match value {
+                Some(value) => Entry::new(key, value),
+                None => Entry::new_deleted_entry(key),
+            })
+            .collect();
+
+        self.append_group_locked(&indexes, &mut shards, entries)
+    }
+
+    // writes a transaction read from a leader's log, keeping the timestamps it was written
+    // with there.
+    pub(crate) fn apply_replicated(&self, entries: Vec<Entry<T>>) -> Result<()> {
+        let indexes = self.indexes.read().unwrap();
+        let mut shards = self
+            .directory
+            .write_shards(entries.iter().map(|entry| &entry.key));
+
+        self.append_group_locked(&indexes, &mut shards, entries)
+    }
+
+    // appends `entries`, one per key, as a single group and applies them, with the shard of
+    // every key already locked.
+    fn append_group_locked(
+        &self,
+        indexes: &HashMap<String, Mutex<SecondaryIndex<T>>>,
+        shards: &mut LockedShards<'_, T>,
+        entries: Vec<Entry<T>>,
+    ) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let writes: Vec<(T, Option<Vec<u8>>)> = entries
+            .iter()
+            .map(|entry| {
+                let value = (!entry.value.is_tombstone()).then(|| entry.value.value.clone());
+                (entry.key.clone(), value)
+            })
+            .collect();
+        let results = self.segments.append_group(entries)?;
+
+        for ((key, value), result) in writes.into_iter().zip(results) {
+            let Some(value) = value else {
+                let previous = shards.remove(&key);
+                self.segment_stats.record_delete(&result, previous.as_ref());
+                self.history.record(&key, &result, true);
+                self.watchers.notify_delete(&key, result.version());
+
+                for index in indexes.values() {
+                    index.lock().unwrap().remove(&key);
+                }
+                continue;
+            };
+
+            let previous = shards.insert(key.clone(), result);
+            self.segment_stats.record_put(&result, previous.as_ref());
+            self.history.record(&key, &result, false);
+            self.watchers.notify_put(&key, &value, result.version());
+
+            for index in indexes.values() {
+                let mut index = index.lock().unwrap();
+                let terms = index.terms(&value);
+                index.put(key.clone(), terms);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn contains(&self, key: T) -> bool {
+        self.directory.contains(&key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.directory.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Size, write time and segment of the key's current value, answered from memory.
+    pub fn stat(&self, key: T) -> Option<KeyMetadata> {
+        let append_entry_response = self.directory.get(&key)?;
+
+        Some(KeyMetadata {
+            value_size: append_entry_response.value_size,
+            timestamp: append_entry_response.timestamp,
+            file_id: append_entry_response.file_id,
+            version: append_entry_response.version(),
+        })
+    }
+
+    /// Iterates over every live `(key, value)` pair. Keys are captured up front, values are
+    /// read from disk one at a time as the iterator advances.
+    pub fn iter(&self) -> KVStoreIter<T, V, C> {
+        let (entries, pin) = self.capture(..);
+
+        KVStoreIter::new(self.segments.clone(), entries, pin)
+    }
+
+    /// Streams every entry appended from `position` on, for consumers that must see each
+    /// mutation in order and resume after a restart from the `next_position` of the last
+    /// event they handled. `Version { file_id: 0, offset: 0 }` starts at the oldest segment
+    /// on disk; any other position must be in a segment that compaction hasn't removed.
+    pub fn changes_from(&self, position: Version) -> Result<ChangeStream<T>> {
+        let wakeup = self.watchers.subscribe(WatchTarget::Prefix(Vec::new()));
+        let position = if position.file_id == 0 && position.offset == 0 {
+            let oldest_file_id = self.segments.file_ids().first().copied().unwrap_or(0);
+            Version {
+                file_id: oldest_file_id,
+                offset: 0,
+            }
+        } else {
+            position
+        };
+        // pinned before the lookup, so the segment can't be removed in between.
+        let pin = self.segments.pin(BTreeSet::from([position.file_id]));
+        self.segments.segment(position.file_id)?;
+
+        Ok(ChangeStream::new(
+            self.segments.clone(),
+            position,
+            pin,
+            wakeup,
+        ))
+    }
+
+    /// The position right after the last entry in the log: where a change stream that has
+    /// caught up stands, and what its `position` reaches once every write made so far has
+    /// been read.
+    pub fn log_position(&self) -> Result<Version> {
+        self.segments.end_position()
+    }
+
+    /// Flushes every write made so far to disk, whatever the store's `SyncPolicy`.
+    pub fn sync(&self) -> Result<()> {
+        self.segments.sync()
+    }
+
+    // seals the active segment and pins every sealed one, as a consistent copy of the store.
+    pub(crate) fn seal_and_pin(&self) -> Result<(Vec<Arc<Segment>>, SegmentPin)> {
+        self.segments.seal_and_pin()
+    }
+
+    pub(crate) fn path(&self) -> &str {
+        self.segments.directory.as_str()
+    }
+
+    /// Subscribes to changes of one key or of every key under a prefix. Events are sent once
+    /// the entry is in the log, in log order per key; a read-only store reports what
+    /// `refresh` picks up. Each watcher buffers up to `KVStoreOptions::watch_buffer_size`
+    /// events and is sent `WatchEvent::Lagged` instead of holding up writers when it falls
+    /// behind.
+    pub fn watch(&self, target: WatchTarget<T>) -> Watcher<T> {
+        self.watchers.subscribe(target)
+    }
+
+    /// Iterates over the live entries whose key falls inside `range`, in key order. With the
+    /// order-preserving key encodings a prefix of a tuple key selects a contiguous run, e.g.
+    /// every `(tenant, timestamp)` of one tenant.
+    pub fn range(&self, range: impl RangeBounds<T>) -> KVStoreIter<T, V, C> {
+        let (entries, pin) = self.capture(range);
+
+        KVStoreIter::in_given_order(self.segments.clone(), entries, pin)
+    }
+
+    /// A read-only view of the store as it is now. Later writes are invisible to it and the
+    /// segments it reads from are kept through compaction until it is dropped.
+    pub fn snapshot(&self) -> Snapshot<T, V, C> {
+        let (entries, pin) = self.capture(..);
+
+        Snapshot::new(self.segments.clone(), entries, pin)
+    }
+
+    // the pin is taken while the directory is still locked, so compaction can't move an
+    // entry out of a segment and delete it between the capture and the pin.
+    fn capture(&self, range: impl RangeBounds<T>) -> (Vec<(T, AppendEntryResponse)>, SegmentPin) {
+        self.directory.range(range, |entries| {
+            self.segments.pin(
+                entries
+                    .iter()
+                    .map(|(_, location)| location.file_id)
+                    .collect(),
+            )
+        })
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = T> + use<T, V, C> {
+        let keys: Vec<T> = self
+            .directory
+            .entries()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        keys.into_iter()
+    }
+
+    /// Live and dead byte counts for every segment, ordered by `file_id`. Use
+    /// `SegmentStats::fragmentation` to pick segments worth compacting.
+    pub fn segment_stats(&self) -> Vec<SegmentStats> {
+        self.segments
+            .file_ids()
+            .into_iter()
+            .map(|file_id| self.segment_stats.get(file_id))
+            .collect()
+    }
+
+    /// Registers a secondary index under `name`. The extractor maps a value, as encoded by the
+    /// store's codec, to the terms it should be found under; existing entries are indexed
+    /// right away.
+    pub fn register_index(&self, name: &str, extractor: IndexExtractor) -> Result<()> {
+        let mut indexes = self.indexes.write().unwrap();
+        indexes.insert(name.to_string(), Mutex::new(SecondaryIndex::new(extractor)));
+
+        self.rebuild_indexes(&indexes)
+    }
+
+    pub fn lookup_index(&self, name: &str, term: &[u8]) -> Result<Vec<T>> {
+        let indexes = self.indexes.read().unwrap();
+        let index = indexes
+            .get(name)
+            .ok_or_else(|| BitcaskError::NotFound(format!("index {}", name)))?;
+
+        Ok(index.lock().unwrap().lookup(term))
+    }
+
+    /// Reclaims the space held by overwritten and deleted entries. The live entries of each
+    /// sealed segment with dead bytes are appended again, keeping their timestamps, and the
+    /// old file is deleted. Segments go oldest first and the pass stops at the first one held
+    /// by a snapshot or iterator, or still holding history inside
+    /// `KVStoreOptions::history_retention`, because a tombstone may only disappear together
+    /// with every older segment that could still hold the value it deletes. Moved entries get
+    /// a new version. Returns the file ids of the removed segments.
+    pub fn compact(&self) -> Result<Vec<u64>> {
+        if self.segments.is_read_only() {
+            return Err(BitcaskError::ReadOnly);
+        }
+
+        // keeps index rebuilds, which read every live entry, from racing the removals.
+        let _indexes = self.indexes.read().unwrap();
+        let _compaction = self.compaction.lock().unwrap();
+        let retention_cutoff = self.history.cutoff();
+        let mut removed = Vec::new();
+
+        for segment in self.segments.sealed_segments() {
+            // a change stream pins only the segment it is reading and relies on every newer
+            // one staying in place too.
+            if self.segments.is_pinned(segment.file_id) {
+                break;
+            }
+
+            // without dead bytes there are no tombstones either and every entry is live, so
+            // the segment can stay without holding back newer ones.
+            if self.segment_stats.get(segment.file_id).dead_bytes == 0 {
+                continue;
+            }
+
+            let entries = segment.read_from::<T>(0, false)?;
+
+            if self.retains_history(segment.file_id, &entries, retention_cutoff) {
+                break;
+            }
+
+            self.relocate_live_entries(segment.file_id, entries)?;
+
+            let file_id = segment.file_id;
+            if !self
+                .history
+                .remove_file(file_id, || self.segments.remove_segment(file_id))?
+            {
+                break;
+            }
+
+            self.segment_stats.remove(segment.file_id);
+            removed.push(segment.file_id);
+        }
+
+        Ok(removed)
+    }
+
+    // entries superseded after the cutoff are still needed to answer `get_at` inside the
+    // retention window.
+    fn retains_history(&self, file_id: u64, entries: &[(Entry<T>, u32, u32)], cutoff: u32) -> bool {
+        entries.iter().any(|(entry, offset, _)| {
+            let version = Version {
+                file_id,
+                offset: *offset as u64,
+            };
+
+            self.history
+                .superseded_at(&entry.key, version)
+                .is_some_and(|superseded_at| superseded_at > cutoff)
+        })
+    }
+
+    fn relocate_live_entries(
+        &self,
+        file_id: u64,
+        entries: Vec<(Entry<T>, u32, u32)>,
+    ) -> Result<()> {
+        for (entry, offset, _) in entries {
+            if entry.value.is_tombstone() {
+                continue;
+            }
+
+            let mut shard = self.directory.write_shard(&entry.key);
+            let is_live = shard.get(&entry.key).is_some_and(|location| {
+                location.file_id == file_id && location.offset == offset as i64
+            });
+
+            if !is_live {
+                continue;
+            }
+
+            let result = self.segments.append_preserving_timestamp(
+                entry.key.clone(),
+                entry.value.value,
+                entry.timestamp,
+            )?;
+            let previous = shard.insert(entry.key.clone(), result);
+            self.segment_stats.record_put(&result, previous.as_ref());
+            self.history.relocate(
+                &entry.key,
+                Version {
+                    file_id,
+                    offset: offset as u64,
+                },
+                &result,
+            );
+        }
+
         Ok(())
     }
 
-    pub fn get(&self, key: T) -> Option<Vec<u8>> {
-        let append_entry_response = self.directory.get(key)?;
-        let mut segments = self.segments.write().unwrap();
+    /// Writes every live key to `writer`, one line per key in key order, with the value as
+    /// encoded by the store's codec and the time it was written. The keys are those live at
+    /// the start of the call. Returns the number of keys written.
+    pub fn export(&self, mut writer: impl Write, format: ExportFormat) -> Result<usize> {
+        let (entries, _pin) = self.capture(..);
+        export::write_header(&mut writer, format)?;
 
-        let result = segments
-            .read::<T>(
+        for (key, append_entry_response) in &entries {
+            let entry = self.segments.read::<T>(
                 append_entry_response.file_id,
                 append_entry_response.entry_length as usize,
                 append_entry_response.offset as u64,
-            )
-            .unwrap();
+            )?;
+
+            export::write_record(
+                &mut writer,
+                format,
+                &ExportRecord {
+                    key: key.serialize()?,
+                    value: entry.value.value,
+                    timestamp: entry.timestamp,
+                },
+            )?;
+        }
+
+        writer.flush()?;
 
-        Some(result.value.value)
+        Ok(entries.len())
     }
 
-    pub fn delete(&mut self, key: T) -> Result<(), std::io::Error> {
-        let mut segments = self.segments.write().unwrap();
-        let _ = segments.append_delete(key.clone());
-        self.directory.remove(key);
+    /// Loads a file written by `export`, overwriting keys that already exist. Each value keeps
+    /// the timestamp it was exported with. Lines are applied as they are read, so an invalid
+    /// line stops the import with the lines before it already written. Returns the number of
+    /// keys imported.
+    pub fn import(&self, reader: impl BufRead, format: ExportFormat) -> Result<usize> {
+        let mut imported = 0;
 
-        Ok(())
+        for record in export::read_records(reader, format) {
+            let record = record?;
+            let key = T::deserialize(record.key)?;
+
+            self.put_when(key, record.value, Some(record.timestamp), |_| true)?;
+            imported += 1;
+        }
+
+        Ok(imported)
+    }
+
+    /// Copies the store into `target`, which must not hold a store yet and is created if
+    /// missing. Writes are held up only while the active segment is sealed; the sealed
+    /// segments are then hard-linked, or copied across filesystems, along with a `MANIFEST`,
+    /// and the result opens with `KVStore::new` showing every write made before the call.
+    pub fn backup(&self, target: impl AsRef<Path>) -> Result<BackupManifest> {
+        let (segments, _pin) = self.segments.seal_and_pin()?;
+
+        backup::write_backup(&segments, target.as_ref())
     }
 
-    fn reload(&mut self) -> Result<(), std::io::Error> {
-        let mut segments = self.segments.write().unwrap();
+    /// The key's value as of `timestamp`, in seconds since the Unix epoch: the last entry
+    /// written at or before it, or `None` if the key was missing or deleted then. Only
+    /// timestamps inside `KVStoreOptions::history_retention` are answered from history;
+    /// without retention this knows no more than the current value and when it was written.
+    pub fn get_at(&self, key: T, timestamp: u32) -> Result<Option<V>> {
+        let (chain, _pin) = self.chain(&key);
+        let Some(link) = chain
+            .iter()
+            .rev()
+            .find(|link| link.location.timestamp <= timestamp)
+        else {
+            return Ok(None);
+        };
+
+        if link.deleted {
+            return Ok(None);
+        }
+
+        Ok(Some(C::decode(self.read_value(&link.location)?)?))
+    }
 
-        for (file_id, segment) in segments.inactive_segments.iter_mut() {
-            let entries = segment.read_full::<T>()?;
+    /// Every revision of the key kept for `KVStoreOptions::history_retention`, oldest first,
+    /// ending with the current one, which is all there is without retention.
+    pub fn history(&self, key: T) -> Result<Vec<Revision<V>>> {
+        let (chain, _pin) = self.chain(&key);
 
-            for (entry, offset, length) in entries {
-                let append_entry_response = AppendEntryResponse {
-                    file_id: *file_id,
-                    offset: offset as i64,
-                    entry_length: length,
+        chain
+            .iter()
+            .map(|link| {
+                let value = if link.deleted {
+                    None
+                } else {
+                    Some(C::decode(self.read_value(&link.location)?)?)
                 };
 
-                self.directory.put(entry.key, append_entry_response);
+                Ok(Revision {
+                    value,
+                    timestamp: link.location.timestamp,
+                    version: link.location.version(),
+                })
+            })
+            .collect()
+    }
+
+    fn chain(&self, key: &T) -> (Vec<Link>, SegmentPin) {
+        if !self.history.is_enabled() {
+            return self.current_link(key);
+        }
+
+        self.history.chain(key, |chain| {
+            self.segments
+                .pin(chain.iter().map(|link| link.location.file_id).collect())
+        })
+    }
+
+    fn current_link(&self, key: &T) -> (Vec<Link>, SegmentPin) {
+        loop {
+            let Some(location) = self.directory.get(key) else {
+                return (Vec::new(), self.segments.pin(BTreeSet::new()));
+            };
+            let pin = self.segments.pin(BTreeSet::from([location.file_id]));
+
+            // compaction only removes a segment after moving its live entries elsewhere, so if
+            // the key still points here now that it is pinned, the segment stays.
+            if self.directory.get(key).map(|current| current.version()) == Some(location.version())
+            {
+                let deleted = false;
+                return (vec![Link { location, deleted }], pin);
+            }
+        }
+    }
+
+    /// Picks up entries and segment files written by another process since the store was
+    /// opened or last refreshed. Only read-only stores can fall behind, on a writable store
+    /// this does nothing.
+    pub fn refresh(&self) -> Result<()> {
+        if !self.segments.is_read_only() {
+            return Ok(());
+        }
+
+        self.segments.refresh()?;
+
+        if self.replay()? > 0 {
+            self.rebuild_indexes(&self.indexes.write().unwrap())?;
+        }
+
+        Ok(())
+    }
+
+    fn reload(&self) -> Result<()> {
+        self.replay()?;
+
+        self.rebuild_indexes(&self.indexes.write().unwrap())
+    }
+
+    // applies every entry past `replayed_up_to` to the key directory and returns how many
+    // there were. Entries of a transaction are held back until the last one of the group is
+    // read. What follows the last complete group in the newest segment, down to a torn
+    // entry, is an append that hasn't finished: a read-only store shares that segment with a
+    // writer that may still be at it and leaves it for the next refresh, while a writable
+    // store has just opened after a crash and cuts the file back to drop it.
+    fn replay(&self) -> Result<usize> {
+        let mut replayed_up_to = self.replayed_up_to.lock().unwrap();
+        let inactive_segments = self.segments.inactive_segments.read().unwrap();
+        let newest_file_id = inactive_segments.keys().next_back().copied();
+        let mut replayed = 0;
+
+        for (file_id, segment) in inactive_segments.range(replayed_up_to.0..) {
+            let start = if *file_id == replayed_up_to.0 {
+                replayed_up_to.1
+            } else {
+                0
+            };
+            let newest = Some(*file_id) == newest_file_id;
+            let mut end = start;
+            let mut group = Vec::new();
+
+            for (entry, offset, length) in segment.read_from::<T>(start, newest)? {
+                let continues_group = entry.value.continues_group();
+                group.push((
+                    entry,
+                    AppendEntryResponse {
+                        file_id: *file_id,
+                        offset: offset as i64,
+                        entry_length: length,
+                        value_size: 0,
+                        timestamp: 0,
+                    },
+                ));
+
+                if continues_group {
+                    continue;
+                }
+
+                end = offset + length;
+
+                for (entry, mut append_entry_response) in group.drain(..) {
+                    append_entry_response.value_size = entry.value.value.len() as u32;
+                    append_entry_response.timestamp = entry.timestamp;
+                    replayed += 1;
+                    self.apply_replayed(entry, append_entry_response);
+                }
+            }
+
+            if newest && !self.segments.is_read_only() && (end as u64) < segment.store.len()? {
+                segment.store.truncate(end as u64)?;
+            }
+
+            // an unfinished group at the end of the newest segment is re-read from its start
+            // on the next refresh, in case the writer is still appending it.
+            *replayed_up_to = (*file_id, end);
+        }
+
+        Ok(replayed)
+    }
+
+    fn apply_replayed(&self, entry: Entry<T>, append_entry_response: AppendEntryResponse) {
+        if entry.value.is_tombstone() {
+            let previous = self.directory.remove(&entry.key);
+            self.segment_stats
+                .record_delete(&append_entry_response, previous.as_ref());
+            self.watchers
+                .notify_delete(&entry.key, append_entry_response.version());
+            self.history
+                .record(&entry.key, &append_entry_response, true);
+            return;
+        }
+
+        self.watchers.notify_put(
+            &entry.key,
+            &entry.value.value,
+            append_entry_response.version(),
+        );
+        self.history
+            .record(&entry.key, &append_entry_response, false);
+        let previous = self.directory.put(entry.key, append_entry_response);
+        self.segment_stats
+            .record_put(&append_entry_response, previous.as_ref());
+    }
+
+    // callers hold the indexes write lock, which keeps writers out until the rebuild is done.
+    fn rebuild_indexes(&self, indexes: &HashMap<String, Mutex<SecondaryIndex<T>>>) -> Result<()> {
+        if indexes.is_empty() {
+            return Ok(());
+        }
+
+        for index in indexes.values() {
+            index.lock().unwrap().clear();
+        }
+
+        for (key, append_entry_response) in self.directory.entries() {
+            let entry = self.segments.read::<T>(
+                append_entry_response.file_id,
+                append_entry_response.entry_length as usize,
+                append_entry_response.offset as u64,
+            )?;
+
+            for index in indexes.values() {
+                let mut index = index.lock().unwrap();
+                let terms = index.terms(&entry.value.value);
+                index.put(key.clone(), terms);
             }
         }
 
@@ -75,9 +894,82 @@ impl<T: entry::key::Serializable> KVStore<T> {
     }
 }
 
+// counters and appends work on the stored bytes themselves, so they are only offered
+// where values are kept as they are.
+impl<T: entry::key::Serializable> KVStore<T, Vec<u8>, RawCodec> {
+    /// Adds `delta` to the integer stored at `key` and returns the result. A missing key counts
+    /// as 0. Values are kept as decimal text, so a plain `get` reads them back as digits.
+    pub fn incr_by(&self, key: T, delta: i64) -> Result<i64> {
+        Ok(self.incr_by_with_version(key, delta)?.0)
+    }
+
+    /// `incr_by`, also returning the version the result was written at.
+    pub fn incr_by_with_version(&self, key: T, delta: i64) -> Result<(i64, Version)> {
+        let mut result = 0;
+        let error_key = key.clone();
+
+        let (_, version) = self.update(key, |current| {
+            let current = match current {
+                Some(value) => std::str::from_utf8(&value)
+                    .ok()
+                    .and_then(|value| value.parse::<i64>().ok())
+                    .ok_or_else(|| {
+                        BitcaskError::InvalidValue(format!(
+                            "value of {:?} is not an integer",
+                            error_key
+                        ))
+                    })?,
+                None => 0,
+            };
+
+            result = current.checked_add(delta).ok_or_else(|| {
+                BitcaskError::InvalidValue(format!("incrementing {:?} would overflow", error_key))
+            })?;
+
+            Ok(result.to_string().into_bytes())
+        })?;
+
+        Ok((result, version))
+    }
+
+    pub fn decr_by(&self, key: T, delta: i64) -> Result<i64> {
+        let delta = delta.checked_neg().ok_or_else(|| {
+            BitcaskError::InvalidValue(format!("cannot decrement {:?} by {}", key, delta))
+        })?;
+
+        self.incr_by(key, delta)
+    }
+
+    /// Appends `suffix` to the value at `key`, creating it if missing, and returns the new
+    /// length of the value.
+    pub fn append(&self, key: T, suffix: &[u8]) -> Result<usize> {
+        Ok(self.append_with_version(key, suffix)?.0)
+    }
+
+    /// `append`, also returning the version the new value was written at.
+    pub fn append_with_version(&self, key: T, suffix: &[u8]) -> Result<(usize, Version)> {
+        let (value, version) = self.update(key, |current| {
+            let mut value = current.unwrap_or_default();
+            value.extend_from_slice(suffix);
+
+            Ok(value)
+        })?;
+
+        Ok((value.len(), version))
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use crate::codec::{JsonCodec, Utf8Codec};
+    use crate::error::BitcaskError;
+    use crate::export::ExportFormat;
     use crate::kv_store::KVStore;
+    use crate::options::{KVStoreOptions, KeyDirectoryBackend};
+    use crate::secondary_index::IndexExtractor;
+    use crate::watch::{WatchEvent, WatchTarget};
+    use serde::{Deserialize, Serialize};
+    use std::sync::Arc;
     use std::thread;
     use std::time::Duration;
     use tempfile::tempdir;
@@ -96,14 +988,14 @@ mod tests {
         let dir = tempdir().unwrap();
         let dir_path = dir.path().to_str().unwrap();
 
-        let mut kv_store = KVStore::<String>::new(dir_path.to_string(), 1024).unwrap();
+        let kv_store = KVStore::<String>::new(dir_path.to_string(), 1024).unwrap();
         let key = String::from("key1");
         let value = vec![1, 2, 3];
 
         let result = kv_store.put(key.clone(), value.clone());
         assert!(result.is_ok());
 
-        let retrived_value = kv_store.get(key);
+        let retrived_value = kv_store.get(key).unwrap();
         assert_eq!(retrived_value, Some(value));
     }
 
@@ -112,7 +1004,7 @@ mod tests {
         let dir = tempdir().unwrap();
         let dir_path = dir.path().to_str().unwrap();
 
-        let mut kv_store = KVStore::<String>::new(dir_path.to_string(), 1024).unwrap();
+        let kv_store = KVStore::<String>::new(dir_path.to_string(), 1024).unwrap();
         let key = String::from("key1");
         let value = vec![1, 2, 3];
 
@@ -125,10 +1017,10 @@ mod tests {
         let result = kv_store.put(key2.clone(), value2.clone());
         assert!(result.is_ok());
 
-        let retrived_value = kv_store.get(key);
+        let retrived_value = kv_store.get(key).unwrap();
         assert_eq!(retrived_value, Some(value));
 
-        let retrived_value = kv_store.get(key2);
+        let retrived_value = kv_store.get(key2).unwrap();
         assert_eq!(retrived_value, Some(value2));
     }
 
@@ -137,9 +1029,9 @@ mod tests {
         let dir = tempdir().unwrap();
         let dir_path = dir.path().to_str().unwrap();
 
-        let mut kv_store = KVStore::<String>::new(dir_path.to_string(), 1024).unwrap();
+        let kv_store = KVStore::<String>::new(dir_path.to_string(), 1024).unwrap();
 
-        let retrived_value = kv_store.get("non-existence_key".to_string());
+        let retrived_value = kv_store.get("non-existence_key".to_string()).unwrap();
         assert_eq!(retrived_value, None);
     }
 
@@ -148,7 +1040,7 @@ mod tests {
         let dir = tempdir().unwrap();
         let dir_path = dir.path().to_str().unwrap();
 
-        let mut kv_store = KVStore::<String>::new(dir_path.to_string(), 1024).unwrap();
+        let kv_store = KVStore::<String>::new(dir_path.to_string(), 1024).unwrap();
         let key = String::from("key1");
         let value = vec![1, 2, 3];
 
@@ -160,7 +1052,7 @@ mod tests {
         let result = kv_store.put(key.clone(), value2.clone());
         assert!(result.is_ok());
 
-        let retrived_value = kv_store.get(key);
+        let retrived_value = kv_store.get(key).unwrap();
         assert_eq!(retrived_value, Some(value2));
     }
 
@@ -168,19 +1060,19 @@ mod tests {
     fn test_delete_key() {
         let dir = tempdir().unwrap();
         let dir_path = dir.path().to_str().unwrap().to_string();
-        let mut kv_store = KVStore::<String>::new(dir_path, 1024).unwrap();
+        let kv_store = KVStore::<String>::new(dir_path, 1024).unwrap();
 
         let key = "key-to-delete".to_string();
         let value = vec![10, 20, 30];
 
         kv_store.put(key.clone(), value.clone()).unwrap();
-        let retrieved_value = kv_store.get(key.clone());
+        let retrieved_value = kv_store.get(key.clone()).unwrap();
         assert_eq!(retrieved_value, Some(value));
 
         let delete_result = kv_store.delete(key.clone());
         assert!(delete_result.is_ok());
 
-        let retrieved_value_after_delete = kv_store.get(key);
+        let retrieved_value_after_delete = kv_store.get(key).unwrap();
         assert_eq!(retrieved_value_after_delete, None);
     }
 
@@ -190,7 +1082,7 @@ mod tests {
         let dir_path = dir.path().to_str().unwrap().to_string();
 
         let max_segment_size = 30;
-        let mut kv_store = KVStore::<String>::new(dir_path, max_segment_size).unwrap();
+        let kv_store = KVStore::<String>::new(dir_path, max_segment_size).unwrap();
 
         let key1 = "key1".to_string();
         let value1 = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 1];
@@ -200,8 +1092,8 @@ mod tests {
         kv_store.put(key1.clone(), value1.clone()).unwrap();
 
         {
-            let segments = kv_store.segments.read().unwrap();
-            assert_eq!(segments.inactive_segments.len(), 0);
+            let inactive_segments = kv_store.segments.inactive_segments.read().unwrap();
+            assert_eq!(inactive_segments.len(), 0);
         }
 
         thread::sleep(Duration::from_secs(2));
@@ -209,15 +1101,1095 @@ mod tests {
         kv_store.put(key2.clone(), value2.clone()).unwrap();
 
         {
-            let segments = kv_store.segments.read().unwrap();
-            assert_eq!(segments.inactive_segments.len(), 1);
+            let inactive_segments = kv_store.segments.inactive_segments.read().unwrap();
+            assert_eq!(inactive_segments.len(), 1);
         }
 
-        let retrieved_value1 = kv_store.get(key1);
+        let retrieved_value1 = kv_store.get(key1).unwrap();
 
         assert_eq!(retrieved_value1, Some(value1));
 
-        let retrieved_value2 = kv_store.get(key2);
+        let retrieved_value2 = kv_store.get(key2).unwrap();
         assert_eq!(retrieved_value2, Some(value2));
     }
+
+    fn email_index() -> IndexExtractor {
+        Box::new(|value: &[u8]| vec![value.to_vec()])
+    }
+
+    #[test]
+    fn test_lookup_index_follows_put_and_delete() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+        let kv_store = KVStore::<String>::new(dir_path, 1024).unwrap();
+
+        kv_store.register_index("email", email_index()).unwrap();
+
+        kv_store
+            .put("user1".to_string(), b"a@example.com".to_vec())
+            .unwrap();
+        kv_store
+            .put("user2".to_string(), b"a@example.com".to_vec())
+            .unwrap();
+
+        let mut keys = kv_store.lookup_index("email", b"a@example.com").unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["user1".to_string(), "user2".to_string()]);
+
+        kv_store
+            .put("user1".to_string(), b"b@example.com".to_vec())
+            .unwrap();
+        kv_store.delete("user2".to_string()).unwrap();
+
+        assert!(
+            kv_store
+                .lookup_index("email", b"a@example.com")
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(
+            kv_store.lookup_index("email", b"b@example.com").unwrap(),
+            vec!["user1".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_lookup_unknown_index() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+        let kv_store = KVStore::<String>::new(dir_path, 1024).unwrap();
+
+        assert!(kv_store.lookup_index("email", b"a@example.com").is_err());
+    }
+
+    #[test]
+    fn test_index_rebuilt_from_existing_entries() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+
+        {
+            let kv_store = KVStore::<String>::new(dir_path.clone(), 1024).unwrap();
+            kv_store
+                .put("user1".to_string(), b"a@example.com".to_vec())
+                .unwrap();
+            kv_store
+                .put("user2".to_string(), b"a@example.com".to_vec())
+                .unwrap();
+            kv_store.delete("user2".to_string()).unwrap();
+        }
+
+        let kv_store = KVStore::<String>::new(dir_path, 1024).unwrap();
+        kv_store.register_index("email", email_index()).unwrap();
+
+        assert_eq!(
+            kv_store.lookup_index("email", b"a@example.com").unwrap(),
+            vec!["user1".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_reload_keeps_latest_value_and_deletes() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+
+        {
+            let kv_store = KVStore::<String>::new(dir_path.clone(), 30).unwrap();
+            kv_store.put("key1".to_string(), vec![1; 20]).unwrap();
+            kv_store.put("key1".to_string(), vec![2; 20]).unwrap();
+            kv_store.put("key2".to_string(), vec![3; 20]).unwrap();
+            kv_store.delete("key2".to_string()).unwrap();
+        }
+
+        let kv_store = KVStore::<String>::new(dir_path, 30).unwrap();
+
+        assert_eq!(kv_store.get("key1".to_string()).unwrap(), Some(vec![2; 20]));
+        assert_eq!(kv_store.get("key2".to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_iter_yields_live_entries() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+        let kv_store = KVStore::<String>::new(dir_path, 30).unwrap();
+
+        kv_store.put("key1".to_string(), vec![1; 20]).unwrap();
+        kv_store.put("key2".to_string(), vec![2; 20]).unwrap();
+        kv_store.put("key1".to_string(), vec![3; 20]).unwrap();
+        kv_store.put("key3".to_string(), vec![4; 20]).unwrap();
+        kv_store.delete("key3".to_string()).unwrap();
+
+        let entries: Vec<(String, Vec<u8>)> = kv_store.iter().map(|entry| entry.unwrap()).collect();
+
+        assert_eq!(
+            entries,
+            vec![
+                ("key2".to_string(), vec![2; 20]),
+                ("key1".to_string(), vec![3; 20]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keys() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+        let kv_store = KVStore::<String>::new(dir_path, 1024).unwrap();
+
+        kv_store.put("key1".to_string(), vec![1]).unwrap();
+        kv_store.put("key2".to_string(), vec![2]).unwrap();
+
+        let mut keys: Vec<String> = kv_store.keys().collect();
+        keys.sort();
+
+        assert_eq!(keys, vec!["key1".to_string(), "key2".to_string()]);
+    }
+
+    #[test]
+    fn test_concurrent_writers_and_readers() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+        let kv_store = Arc::new(KVStore::<String>::new(dir_path, 256).unwrap());
+
+        let handles: Vec<_> = (0..8)
+            .map(|writer| {
+                let kv_store = kv_store.clone();
+                thread::spawn(move || {
+                    for i in 0..50 {
+                        let key = format!("key-{}-{}", writer, i);
+                        kv_store
+                            .put(key.clone(), vec![writer as u8, i as u8])
+                            .unwrap();
+                        assert_eq!(
+                            kv_store.get(key).unwrap(),
+                            Some(vec![writer as u8, i as u8])
+                        );
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(kv_store.keys().count(), 8 * 50);
+        assert_eq!(
+            kv_store.get("key-3-7".to_string()).unwrap(),
+            Some(vec![3, 7])
+        );
+    }
+
+    #[test]
+    fn test_segment_stats_track_superseded_entries() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+
+        {
+            let kv_store = KVStore::<String>::new(dir_path.clone(), 1024).unwrap();
+            kv_store.put("key1".to_string(), vec![1; 10]).unwrap();
+            kv_store.put("key2".to_string(), vec![2; 10]).unwrap();
+            kv_store.put("key1".to_string(), vec![3; 10]).unwrap();
+            kv_store.delete("key2".to_string()).unwrap();
+
+            let stats = kv_store.segment_stats();
+            assert_eq!(stats.len(), 1);
+            assert_eq!(stats[0].live_keys, 1);
+            assert_eq!(stats[0].tombstones, 1);
+            assert!(stats[0].dead_bytes > stats[0].live_bytes);
+        }
+
+        let kv_store = KVStore::<String>::new(dir_path, 1024).unwrap();
+        let stats = kv_store.segment_stats();
+
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].live_keys, 1);
+        assert_eq!(stats[0].tombstones, 1);
+        assert!(stats[0].fragmentation() > 0.5);
+        assert_eq!(stats[1].live_keys, 0);
+        assert_eq!(stats[1].fragmentation(), 0.0);
+    }
+
+    #[test]
+    fn test_metadata_queries() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+
+        {
+            let kv_store = KVStore::<String>::new(dir_path.clone(), 1024).unwrap();
+            assert!(kv_store.is_empty());
+
+            kv_store.put("key1".to_string(), vec![1, 2, 3]).unwrap();
+            kv_store.put("key2".to_string(), vec![4]).unwrap();
+            kv_store.delete("key2".to_string()).unwrap();
+
+            assert!(kv_store.contains("key1".to_string()));
+            assert!(!kv_store.contains("key2".to_string()));
+            assert_eq!(kv_store.len(), 1);
+        }
+
+        let kv_store = KVStore::<String>::new(dir_path, 1024).unwrap();
+        let metadata = kv_store.stat("key1".to_string()).unwrap();
+
+        assert_eq!(metadata.value_size, 3);
+        assert!(metadata.timestamp > 0);
+        assert_eq!(kv_store.len(), 1);
+        assert_eq!(kv_store.stat("key2".to_string()), None);
+    }
+
+    #[test]
+    fn test_put_if_absent() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+        let kv_store = KVStore::<String>::new(dir_path, 1024).unwrap();
+
+        assert!(
+            kv_store
+                .put_if_absent("key1".to_string(), vec![1])
+                .unwrap()
+                .is_some()
+        );
+        assert_eq!(
+            kv_store.put_if_absent("key1".to_string(), vec![2]).unwrap(),
+            None
+        );
+        assert_eq!(kv_store.get("key1".to_string()).unwrap(), Some(vec![1]));
+
+        kv_store.delete("key1".to_string()).unwrap();
+        assert!(
+            kv_store
+                .put_if_absent("key1".to_string(), vec![3])
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_compare_and_swap_and_delete_if_version() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+        let kv_store = KVStore::<String>::new(dir_path, 1024).unwrap();
+
+        kv_store.put("key1".to_string(), vec![1]).unwrap();
+        let (_, first_version) = kv_store
+            .get_with_version("key1".to_string())
+            .unwrap()
+            .unwrap();
+
+        let second_version = kv_store
+            .compare_and_swap("key1".to_string(), first_version, vec![2])
+            .unwrap()
+            .unwrap();
+        assert!(second_version > first_version);
+        assert_eq!(
+            kv_store.stat("key1".to_string()).unwrap().version,
+            second_version
+        );
+
+        assert_eq!(
+            kv_store
+                .compare_and_swap("key1".to_string(), first_version, vec![3])
+                .unwrap(),
+            None
+        );
+        assert!(
+            !kv_store
+                .delete_if_version("key1".to_string(), first_version)
+                .unwrap()
+        );
+        assert_eq!(kv_store.get("key1".to_string()).unwrap(), Some(vec![2]));
+
+        assert!(
+            kv_store
+                .delete_if_version("key1".to_string(), second_version)
+                .unwrap()
+        );
+        assert_eq!(kv_store.get("key1".to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_concurrent_compare_and_swap_loses_no_updates() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+        let kv_store = Arc::new(KVStore::<String>::new(dir_path, 4096).unwrap());
+        kv_store.put("counter".to_string(), vec![0]).unwrap();
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let kv_store = kv_store.clone();
+                thread::spawn(move || {
+                    for _ in 0..25 {
+                        loop {
+                            let (value, version) = kv_store
+                                .get_with_version("counter".to_string())
+                                .unwrap()
+                                .unwrap();
+                            let swapped = kv_store
+                                .compare_and_swap(
+                                    "counter".to_string(),
+                                    version,
+                                    vec![value[0] + 1],
+                                )
+                                .unwrap();
+
+                            if swapped.is_some() {
+                                break;
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(
+            kv_store.get("counter".to_string()).unwrap(),
+            Some(vec![100])
+        );
+    }
+
+    #[test]
+    fn test_incr_and_decr() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+        let kv_store = KVStore::<String>::new(dir_path, 1024).unwrap();
+
+        assert_eq!(kv_store.incr_by("counter".to_string(), 1).unwrap(), 1);
+        assert_eq!(kv_store.incr_by("counter".to_string(), 10).unwrap(), 11);
+        assert_eq!(kv_store.decr_by("counter".to_string(), 12).unwrap(), -1);
+        assert_eq!(
+            kv_store.get("counter".to_string()).unwrap(),
+            Some(b"-1".to_vec())
+        );
+
+        kv_store
+            .put("name".to_string(), b"bitcask".to_vec())
+            .unwrap();
+        assert!(matches!(
+            kv_store.incr_by("name".to_string(), 1),
+            Err(BitcaskError::InvalidValue(_))
+        ));
+
+        kv_store
+            .put("max".to_string(), i64::MAX.to_string().into_bytes())
+            .unwrap();
+        assert!(matches!(
+            kv_store.incr_by("max".to_string(), 1),
+            Err(BitcaskError::InvalidValue(_))
+        ));
+    }
+
+    #[test]
+    fn test_append() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+        let kv_store = KVStore::<String>::new(dir_path, 1024).unwrap();
+
+        assert_eq!(kv_store.append("log".to_string(), b"hello").unwrap(), 5);
+        assert_eq!(kv_store.append("log".to_string(), b" world").unwrap(), 11);
+        assert_eq!(
+            kv_store.get("log".to_string()).unwrap(),
+            Some(b"hello world".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_writes_return_their_version() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+        let kv_store = KVStore::<String>::new(dir_path, 1024).unwrap();
+        let version_of = |key: &str| kv_store.stat(key.to_string()).unwrap().version;
+
+        let version = kv_store
+            .put_with_version("key".to_string(), b"value".to_vec())
+            .unwrap();
+        assert_eq!(version, version_of("key"));
+
+        let (value, version) = kv_store
+            .incr_by_with_version("counter".to_string(), 2)
+            .unwrap();
+        assert_eq!((value, version), (2, version_of("counter")));
+
+        let (length, version) = kv_store
+            .append_with_version("log".to_string(), b"hello")
+            .unwrap();
+        assert_eq!((length, version), (5, version_of("log")));
+        assert!(version > version_of("counter"));
+
+        let deleted = kv_store.delete_with_version("log".to_string()).unwrap();
+        assert!(deleted > version);
+        assert_eq!(kv_store.log_position().unwrap().file_id, deleted.file_id);
+        assert!(kv_store.log_position().unwrap() > deleted);
+    }
+
+    #[test]
+    fn test_concurrent_incr_loses_no_updates() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+        let kv_store = Arc::new(KVStore::<String>::new(dir_path, 4096).unwrap());
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let kv_store = kv_store.clone();
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        kv_store.incr_by("counter".to_string(), 1).unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(
+            kv_store.get("counter".to_string()).unwrap(),
+            Some(b"400".to_vec())
+        );
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        age: u32,
+    }
+
+    #[test]
+    fn test_typed_values_through_codec() {
+        let dir = tempdir().unwrap();
+        let ada = User {
+            name: "ada".to_string(),
+            age: 36,
+        };
+
+        {
+            let kv_store: KVStore<String, User, JsonCodec> =
+                KVStoreOptions::new().open(dir.path()).unwrap();
+            kv_store
+                .put(
+                    "user1".to_string(),
+                    User {
+                        name: "ada".to_string(),
+                        age: 36,
+                    },
+                )
+                .unwrap();
+        }
+
+        let kv_store: KVStore<String, User, JsonCodec> =
+            KVStoreOptions::new().open(dir.path()).unwrap();
+        assert_eq!(kv_store.get("user1".to_string()).unwrap(), Some(ada));
+
+        let entries: Vec<(String, User)> = kv_store.iter().map(|entry| entry.unwrap()).collect();
+        assert_eq!(entries.len(), 1);
+        drop(kv_store);
+
+        let strings: KVStore<String, String, Utf8Codec> =
+            KVStoreOptions::new().open(dir.path()).unwrap();
+        assert_eq!(
+            strings.get("user1".to_string()).unwrap(),
+            Some(r#"{"name":"ada","age":36}"#.to_string())
+        );
+    }
+
+    #[test]
+    fn test_range_over_tuple_keys() {
+        let dir = tempdir().unwrap();
+
+        for backend in [KeyDirectoryBackend::Hash, KeyDirectoryBackend::Ordered] {
+            let kv_store: KVStore<(String, u64)> = KVStoreOptions::new()
+                .key_directory_backend(backend)
+                .open(dir.path())
+                .unwrap();
+
+            for (tenant, timestamp) in [("b", 1), ("a", 20), ("a", 3), ("ab", 1), ("a", 100)] {
+                kv_store
+                    .put((tenant.to_string(), timestamp), vec![timestamp as u8])
+                    .unwrap();
+            }
+
+            let tenant_a: Vec<(String, u64)> = kv_store
+                .range(("a".to_string(), 0)..=("a".to_string(), u64::MAX))
+                .map(|entry| entry.unwrap().0)
+                .collect();
+
+            assert_eq!(
+                tenant_a,
+                vec![
+                    ("a".to_string(), 3),
+                    ("a".to_string(), 20),
+                    ("a".to_string(), 100)
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn test_watch_key_and_prefix() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+        let kv_store = KVStore::<String>::new(dir_path, 1024).unwrap();
+
+        let key_watcher = kv_store.watch(WatchTarget::Key("user:1".to_string()));
+        let prefix_watcher = kv_store.watch(WatchTarget::Prefix(b"user:".to_vec()));
+
+        kv_store.put("user:1".to_string(), vec![1]).unwrap();
+        kv_store.put("order:1".to_string(), vec![2]).unwrap();
+        kv_store.incr_by("user:2".to_string(), 5).unwrap();
+        kv_store.delete("user:1".to_string()).unwrap();
+
+        let version = kv_store.stat("user:2".to_string()).unwrap().version;
+        let events: Vec<WatchEvent<String>> =
+            std::iter::from_fn(|| prefix_watcher.try_recv()).collect();
+        assert_eq!(events.len(), 3);
+        assert_eq!(
+            events[1],
+            WatchEvent::Put {
+                key: "user:2".to_string(),
+                value: b"5".to_vec(),
+                version
+            }
+        );
+        assert!(matches!(events[2], WatchEvent::Delete { .. }));
+
+        assert!(matches!(
+            key_watcher.try_recv(),
+            Some(WatchEvent::Put { .. })
+        ));
+        assert!(matches!(
+            key_watcher.try_recv(),
+            Some(WatchEvent::Delete { .. })
+        ));
+        assert_eq!(key_watcher.try_recv(), None);
+    }
+
+    #[test]
+    fn test_snapshot_ignores_later_writes() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+        let kv_store = KVStore::<String>::new(dir_path, 30).unwrap();
+
+        kv_store.put("key1".to_string(), vec![1; 20]).unwrap();
+        kv_store.put("key2".to_string(), vec![2; 20]).unwrap();
+        let snapshot = kv_store.snapshot();
+
+        kv_store.put("key1".to_string(), vec![3; 20]).unwrap();
+        kv_store.delete("key2".to_string()).unwrap();
+        kv_store.put("key3".to_string(), vec![4; 20]).unwrap();
+
+        assert_eq!(snapshot.get("key1".to_string()).unwrap(), Some(vec![1; 20]));
+        assert_eq!(snapshot.get("key2".to_string()).unwrap(), Some(vec![2; 20]));
+        assert!(!snapshot.contains("key3".to_string()));
+        assert_eq!(
+            snapshot
+                .iter()
+                .map(|entry| entry.unwrap().0)
+                .collect::<Vec<_>>(),
+            vec!["key1".to_string(), "key2".to_string()]
+        );
+        assert_eq!(kv_store.get("key1".to_string()).unwrap(), Some(vec![3; 20]));
+    }
+
+    #[test]
+    fn test_compaction_reclaims_dead_entries() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+
+        {
+            let kv_store = KVStore::<String>::new(dir_path.clone(), 64).unwrap();
+
+            for round in 0..5u8 {
+                kv_store.put("key1".to_string(), vec![round; 20]).unwrap();
+                kv_store.put("key2".to_string(), vec![round; 20]).unwrap();
+            }
+            kv_store.put("key3".to_string(), vec![9; 20]).unwrap();
+            kv_store.delete("key2".to_string()).unwrap();
+            kv_store.put("key4".to_string(), vec![7; 20]).unwrap();
+
+            let segments_before = kv_store.segments.file_ids().len();
+            let removed = kv_store.compact().unwrap();
+
+            assert!(!removed.is_empty());
+            assert!(kv_store.segments.file_ids().len() < segments_before);
+            for file_id in removed {
+                assert!(!kv_store.segments.file_ids().contains(&file_id));
+            }
+            assert_eq!(kv_store.get("key1".to_string()).unwrap(), Some(vec![4; 20]));
+            assert_eq!(kv_store.get("key2".to_string()).unwrap(), None);
+        }
+
+        let kv_store = KVStore::<String>::new(dir_path, 64).unwrap();
+
+        assert_eq!(kv_store.get("key1".to_string()).unwrap(), Some(vec![4; 20]));
+        assert_eq!(kv_store.get("key2".to_string()).unwrap(), None);
+        assert_eq!(kv_store.get("key3".to_string()).unwrap(), Some(vec![9; 20]));
+        assert_eq!(kv_store.get("key4".to_string()).unwrap(), Some(vec![7; 20]));
+        assert_eq!(kv_store.len(), 3);
+    }
+
+    #[test]
+    fn test_compaction_keeps_pinned_segments() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+        let kv_store = KVStore::<String>::new(dir_path, 30).unwrap();
+
+        kv_store.put("key1".to_string(), vec![1; 20]).unwrap();
+        kv_store.put("key2".to_string(), vec![2; 20]).unwrap();
+        let snapshot = kv_store.snapshot();
+        kv_store.put("key1".to_string(), vec![3; 20]).unwrap();
+        kv_store.put("key2".to_string(), vec![4; 20]).unwrap();
+
+        assert!(kv_store.compact().unwrap().is_empty());
+        assert_eq!(snapshot.get("key1".to_string()).unwrap(), Some(vec![1; 20]));
+
+        drop(snapshot);
+
+        assert_eq!(kv_store.compact().unwrap().len(), 2);
+        assert_eq!(kv_store.get("key1".to_string()).unwrap(), Some(vec![3; 20]));
+        assert_eq!(kv_store.get("key2".to_string()).unwrap(), Some(vec![4; 20]));
+    }
+
+    #[test]
+    fn test_transaction_commits_writes_together() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+
+        {
+            let kv_store = KVStore::<String>::new(dir_path.clone(), 1024).unwrap();
+            kv_store.put("from".to_string(), b"10".to_vec()).unwrap();
+            kv_store.put("stale".to_string(), vec![1]).unwrap();
+
+            let mut transaction = kv_store.transaction();
+            assert_eq!(
+                transaction.get("from".to_string()).unwrap(),
+                Some(b"10".to_vec())
+            );
+            transaction.put("from".to_string(), b"3".to_vec()).unwrap();
+            transaction.put("to".to_string(), b"7".to_vec()).unwrap();
+            transaction.delete("stale".to_string());
+
+            assert_eq!(
+                transaction.get("to".to_string()).unwrap(),
+                Some(b"7".to_vec())
+            );
+            assert_eq!(transaction.get("stale".to_string()).unwrap(), None);
+            assert_eq!(kv_store.get("to".to_string()).unwrap(), None);
+
+            transaction.commit().unwrap();
+            assert_eq!(kv_store.get("to".to_string()).unwrap(), Some(b"7".to_vec()));
+        }
+
+        let kv_store = KVStore::<String>::new(dir_path, 1024).unwrap();
+
+        assert_eq!(
+            kv_store.get("from".to_string()).unwrap(),
+            Some(b"3".to_vec())
+        );
+        assert_eq!(kv_store.get("to".to_string()).unwrap(), Some(b"7".to_vec()));
+        assert_eq!(kv_store.get("stale".to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_transaction_conflict_writes_nothing() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+        let kv_store = KVStore::<String>::new(dir_path, 1024).unwrap();
+        kv_store.put("key1".to_string(), vec![1]).unwrap();
+
+        let mut transaction = kv_store.transaction();
+        transaction.get("key1".to_string()).unwrap();
+        transaction.get("missing".to_string()).unwrap();
+        transaction.put("key2".to_string(), vec![2]).unwrap();
+
+        kv_store.put("missing".to_string(), vec![3]).unwrap();
+
+        assert!(matches!(
+            transaction.commit(),
+            Err(BitcaskError::Conflict(_))
+        ));
+        assert_eq!(kv_store.get("key2".to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_unfinished_transaction_is_dropped_on_reload() {
+        // as if the process died after writing the first entry of the group, and then
+        // partway through the second one.
+        for torn_bytes in [0, 7] {
+            let dir = tempdir().unwrap();
+            let dir_path = dir.path().to_str().unwrap().to_string();
+            let (segment_path, group_start, cut_at) = {
+                let kv_store = KVStore::<String>::new(dir_path.clone(), 1024).unwrap();
+                kv_store.put("a".to_string(), vec![0]).unwrap();
+                let group_start = kv_store.log_position().unwrap().offset;
+
+                let mut transaction = kv_store.transaction();
+                transaction.put("a".to_string(), vec![1]).unwrap();
+                transaction.put("b".to_string(), vec![2]).unwrap();
+                transaction.commit().unwrap();
+
+                let segment = kv_store.segments.active_segment().unwrap();
+                let last = kv_store.stat("b".to_string()).unwrap().version;
+                (
+                    segment.file_path.clone(),
+                    group_start,
+                    last.offset + torn_bytes,
+                )
+            };
+
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(&segment_path)
+                .unwrap()
+                .set_len(cut_at)
+                .unwrap();
+
+            let kv_store = KVStore::<String>::new(dir_path.clone(), 1024).unwrap();
+
+            assert_eq!(kv_store.get("a".to_string()).unwrap(), Some(vec![0]));
+            assert_eq!(kv_store.get("b".to_string()).unwrap(), None);
+            assert_eq!(std::fs::metadata(&segment_path).unwrap().len(), group_start);
+
+            kv_store.put("b".to_string(), vec![3]).unwrap();
+            drop(kv_store);
+            let kv_store = KVStore::<String>::new(dir_path, 1024).unwrap();
+            assert_eq!(kv_store.get("b".to_string()).unwrap(), Some(vec![3]));
+        }
+    }
+
+    #[test]
+    fn test_concurrent_transfers_keep_the_total() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+        let kv_store = Arc::new(KVStore::<String>::new(dir_path, 4096).unwrap());
+        kv_store.put("left".to_string(), b"100".to_vec()).unwrap();
+        kv_store.put("right".to_string(), b"100".to_vec()).unwrap();
+
+        let balance = |value: Option<Vec<u8>>| -> i64 {
+            String::from_utf8(value.unwrap()).unwrap().parse().unwrap()
+        };
+
+        let handles: Vec<_> = (0..4)
+            .map(|thread| {
+                let kv_store = kv_store.clone();
+                thread::spawn(move || {
+                    let (from, to) = if thread % 2 == 0 {
+                        ("left", "right")
+                    } else {
+                        ("right", "left")
+                    };
+
+                    for _ in 0..25 {
+                        loop {
+                            let mut transaction = kv_store.transaction();
+                            let from_balance = balance(transaction.get(from.to_string()).unwrap());
+                            let to_balance = balance(transaction.get(to.to_string()).unwrap());
+                            transaction
+                                .put(
+                                    from.to_string(),
+                                    (from_balance - 1).to_string().into_bytes(),
+                                )
+                                .unwrap();
+                            transaction
+                                .put(to.to_string(), (to_balance + 1).to_string().into_bytes())
+                                .unwrap();
+
+                            match transaction.commit() {
+                                Ok(()) => break,
+                                Err(BitcaskError::Conflict(_)) => continue,
+                                Err(error) => panic!("{}", error),
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let left = balance(kv_store.get("left".to_string()).unwrap());
+        let right = balance(kv_store.get("right".to_string()).unwrap());
+        assert_eq!(left + right, 200);
+    }
+
+    #[test]
+    fn test_history_and_get_at() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+
+        let open = |dir_path: &str| -> KVStore<String> {
+            KVStoreOptions::new()
+                .max_segment_size(30)
+                .history_retention(Duration::from_secs(3600))
+                .open(dir_path)
+                .unwrap()
+        };
+
+        {
+            let kv_store = open(&dir_path);
+            kv_store.put("key1".to_string(), vec![1]).unwrap();
+            thread::sleep(Duration::from_millis(1100));
+            kv_store.put("key1".to_string(), vec![2]).unwrap();
+            kv_store.delete("key1".to_string()).unwrap();
+            kv_store.put("key1".to_string(), vec![3]).unwrap();
+        }
+
+        let kv_store = open(&dir_path);
+        let history = kv_store.history("key1".to_string()).unwrap();
+
+        assert_eq!(
+            history
+                .iter()
+                .map(|revision| revision.value.clone())
+                .collect::<Vec<_>>(),
+            vec![Some(vec![1]), Some(vec![2]), None, Some(vec![3])]
+        );
+        assert_eq!(
+            history.last().unwrap().version,
+            kv_store.stat("key1".to_string()).unwrap().version
+        );
+
+        let first_written = history[0].timestamp;
+        assert_eq!(
+            kv_store
+                .get_at("key1".to_string(), first_written - 1)
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            kv_store.get_at("key1".to_string(), first_written).unwrap(),
+            Some(vec![1])
+        );
+        assert_eq!(
+            kv_store.get_at("key1".to_string(), u32::MAX).unwrap(),
+            Some(vec![3])
+        );
+        assert!(kv_store.history("missing".to_string()).unwrap().is_empty());
+
+        drop(kv_store);
+        let kv_store = KVStore::<String>::new(dir_path, 30).unwrap();
+        let history = kv_store.history("key1".to_string()).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].value, Some(vec![3]));
+        assert_eq!(
+            kv_store.get_at("key1".to_string(), first_written).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_compaction_honours_history_retention() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+
+        {
+            let kv_store: KVStore<String> = KVStoreOptions::new()
+                .max_segment_size(30)
+                .history_retention(Duration::from_secs(3600))
+                .open(dir_path.clone())
+                .unwrap();
+            kv_store.put("key1".to_string(), vec![1; 20]).unwrap();
+            kv_store.put("key1".to_string(), vec![2; 20]).unwrap();
+            kv_store.put("key2".to_string(), vec![3; 20]).unwrap();
+
+            assert!(kv_store.compact().unwrap().is_empty());
+            assert_eq!(kv_store.history("key1".to_string()).unwrap().len(), 2);
+        }
+
+        let kv_store = KVStore::<String>::new(dir_path, 30).unwrap();
+
+        assert_eq!(kv_store.compact().unwrap().len(), 1);
+        let history = kv_store.history("key1".to_string()).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].value, Some(vec![2; 20]));
+    }
+
+    #[test]
+    fn test_export_and_import_preserve_timestamps() {
+        for format in [ExportFormat::JsonLines, ExportFormat::Csv] {
+            let source_dir = tempdir().unwrap();
+            let target_dir = tempdir().unwrap();
+            let source =
+                KVStore::<String>::new(source_dir.path().to_str().unwrap().to_string(), 64)
+                    .unwrap();
+            let target =
+                KVStore::<String>::new(target_dir.path().to_str().unwrap().to_string(), 64)
+                    .unwrap();
+
+            source.put("text".to_string(), b"hello".to_vec()).unwrap();
+            source
+                .put("binary".to_string(), vec![0, 255, 10, 44])
+                .unwrap();
+            source.put("gone".to_string(), vec![1]).unwrap();
+            source.delete("gone".to_string()).unwrap();
+
+            let mut exported = Vec::new();
+            assert_eq!(source.export(&mut exported, format).unwrap(), 2);
+            assert_eq!(target.import(exported.as_slice(), format).unwrap(), 2);
+
+            assert_eq!(target.len(), 2);
+            for key in ["text", "binary"] {
+                assert_eq!(
+                    target.get(key.to_string()).unwrap(),
+                    source.get(key.to_string()).unwrap()
+                );
+                assert_eq!(
+                    target.stat(key.to_string()).unwrap().timestamp,
+                    source.stat(key.to_string()).unwrap().timestamp
+                );
+            }
+
+            let csv = b"key,value,timestamp\ndXNlcg==,AQI=,12\n";
+            target.import(&csv[..], ExportFormat::Csv).unwrap();
+
+            let metadata = target.stat("user".to_string()).unwrap();
+            assert_eq!(metadata.timestamp, 12);
+            assert_eq!(target.get("user".to_string()).unwrap(), Some(vec![1, 2]));
+        }
+    }
+
+    #[test]
+    fn test_read_only_rejects_writes() {
+        let dir = tempdir().unwrap();
+
+        {
+            let kv_store =
+                KVStore::<String>::new(dir.path().to_str().unwrap().to_string(), 1024).unwrap();
+            kv_store.put("key1".to_string(), vec![1]).unwrap();
+        }
+
+        let files_before = std::fs::read_dir(dir.path()).unwrap().count();
+
+        let kv_store: KVStore<String> = KVStoreOptions::new()
+            .read_only(true)
+            .open(dir.path())
+            .unwrap();
+
+        assert_eq!(kv_store.get("key1".to_string()).unwrap(), Some(vec![1]));
+        assert!(matches!(
+            kv_store.put("key2".to_string(), vec![2]),
+            Err(BitcaskError::ReadOnly)
+        ));
+        assert!(matches!(
+            kv_store.delete("key1".to_string()),
+            Err(BitcaskError::ReadOnly)
+        ));
+        assert!(matches!(kv_store.compact(), Err(BitcaskError::ReadOnly)));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), files_before);
+    }
+
+    #[test]
+    fn test_read_only_refresh_follows_running_writer() {
+        let dir = tempdir().unwrap();
+        let writer: KVStore<String> = KVStoreOptions::new()
+            .max_segment_size(30)
+            .open(dir.path())
+            .unwrap();
+        writer.put("key1".to_string(), vec![1; 20]).unwrap();
+
+        let reader: KVStore<String> = KVStoreOptions::new()
+            .read_only(true)
+            .open(dir.path())
+            .unwrap();
+        assert_eq!(reader.get("key1".to_string()).unwrap(), Some(vec![1; 20]));
+
+        writer.put("key2".to_string(), vec![2; 20]).unwrap();
+        writer.delete("key1".to_string()).unwrap();
+        assert_eq!(reader.get("key2".to_string()).unwrap(), None);
+
+        reader.refresh().unwrap();
+
+        assert_eq!(reader.get("key1".to_string()).unwrap(), None);
+        assert_eq!(reader.get("key2".to_string()).unwrap(), Some(vec![2; 20]));
+        assert_eq!(reader.len(), 1);
+    }
+
+    #[test]
+    fn test_read_only_skips_torn_tail_of_active_segment() {
+        let dir = tempdir().unwrap();
+        let writer: KVStore<String> = KVStoreOptions::new().open(dir.path()).unwrap();
+        writer.put("key1".to_string(), vec![1, 2, 3]).unwrap();
+
+        // a header announcing more bytes than have reached the file yet
+        let active_segment = writer.segments.active_segment().unwrap();
+        let mut torn_entry = Vec::new();
+        torn_entry.extend_from_slice(&1u32.to_le_bytes());
+        torn_entry.extend_from_slice(&4u32.to_le_bytes());
+        torn_entry.extend_from_slice(&4u32.to_le_bytes());
+        torn_entry.extend_from_slice(b"ke");
+        active_segment.store.append(&torn_entry).unwrap();
+
+        let reader: KVStore<String> = KVStoreOptions::new()
+            .read_only(true)
+            .open(dir.path())
+            .unwrap();
+
+        assert_eq!(reader.get("key1".to_string()).unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(reader.len(), 1);
+    }
+
+    #[test]
+    fn test_ordered_backend_yields_sorted_keys() {
+        let dir = tempdir().unwrap();
+
+        let kv_store: KVStore<String> = KVStoreOptions::new()
+            .key_directory_backend(KeyDirectoryBackend::Ordered)
+            .open(dir.path())
+            .unwrap();
+
+        for key in ["c", "a", "b"] {
+            kv_store.put(key.to_string(), vec![1]).unwrap();
+        }
+
+        let keys: Vec<String> = kv_store.keys().collect();
+        assert_eq!(keys, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_second_writer_is_locked_out() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+
+        let _kv_store = KVStore::<String>::new(dir_path.clone(), 1024).unwrap();
+        let result = KVStore::<String>::new(dir_path, 1024);
+
+        assert!(matches!(result, Err(BitcaskError::Locked(_))));
+    }
+
+    #[test]
+    fn test_corrupted_segment_is_reported() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path().to_str().unwrap().to_string();
+
+        {
+            let kv_store = KVStore::<String>::new(dir_path.clone(), 1024).unwrap();
+            kv_store.put("key1".to_string(), vec![1, 2, 3]).unwrap();
+        }
+
+        let segment_path = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "data")
+                    && path.metadata().unwrap().len() > 0
+            })
+            .unwrap();
+        // sizes that reach past the end of the file would pass for an append cut short by a
+        // crash; a value too small to hold the flags byte can't be one.
+        let mut bytes = std::fs::read(&segment_path).unwrap();
+        bytes[8..12].copy_from_slice(&0u32.to_le_bytes());
+        std::fs::write(&segment_path, bytes).unwrap();
+
+        let result = KVStore::<String>::new(dir_path, 1024);
+
+        assert!(matches!(
+            result,
+            Err(BitcaskError::Corruption { offset: 0, .. })
+        ));
+    }
 }
@@ -0,0 +1,67 @@
+use crate::codec::{RawCodec, ValueCodec};
+use crate::entry;
+use crate::error::Result;
+use crate::segment::AppendEntryResponse;
+use crate::segments::{SegmentPin, Segments};
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::vec::IntoIter;
+
+/// Walks the live entries captured when the iterator was created. `KVStore::iter` orders them
+/// by their location on disk so segments are read front to back, `KVStore::range` by key.
+/// Values are only read when the entry is reached.
+pub struct KVStoreIter<T: entry::key::Serializable, V = Vec<u8>, C: ValueCodec<V> = RawCodec> {
+    segments: Arc<Segments>,
+    locations: IntoIter<(T, AppendEntryResponse)>,
+    _pin: SegmentPin,
+    codec: PhantomData<fn() -> (V, C)>,
+}
+
+impl<T: entry::key::Serializable, V, C: ValueCodec<V>> KVStoreIter<T, V, C> {
+    pub(crate) fn new(
+        segments: Arc<Segments>,
+        mut locations: Vec<(T, AppendEntryResponse)>,
+        pin: SegmentPin,
+    ) -> KVStoreIter<T, V, C> {
+        locations.sort_by_key(|(_, location)| (location.file_id, location.offset));
+
+        Self::in_given_order(segments, locations, pin)
+    }
+
+    // `pin` keeps the segments behind `locations` from being compacted away mid-iteration.
+    pub(crate) fn in_given_order(
+        segments: Arc<Segments>,
+        locations: Vec<(T, AppendEntryResponse)>,
+        pin: SegmentPin,
+    ) -> KVStoreIter<T, V, C> {
+        KVStoreIter {
+            segments,
+            locations: locations.into_iter(),
+            _pin: pin,
+            codec: PhantomData,
+        }
+    }
+}
+
+impl<T: entry::key::Serializable, V, C: ValueCodec<V>> Iterator for KVStoreIter<T, V, C> {
+    type Item = Result<(T, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, location) = self.locations.next()?;
+
+        let result = self
+            .segments
+            .read::<T>(
+                location.file_id,
+                location.entry_length as usize,
+                location.offset as u64,
+            )
+            .and_then(|entry| Ok((key, C::decode(entry.value.value)?)));
+
+        Some(result)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.locations.size_hint()
+    }
+}
@@ -0,0 +1,60 @@
+//! A log-structured key-value store in the style of Bitcask.
+//!
+//! ```
+//! use bitcask::KVStore;
+//!
+//! let dir = tempfile::tempdir().unwrap();
+//! let store = KVStore::<String>::new(dir.path().to_str().unwrap().to_string(), 1024 * 1024).unwrap();
+//!
+//! store.put("name".to_string(), b"bitcask".to_vec()).unwrap();
+//! assert_eq!(store.get("name".to_string()).unwrap(), Some(b"bitcask".to_vec()));
+//! ```
+
+mod async_kv_store;
+mod backup;
+mod changes;
+mod codec;
+mod entry;
+mod error;
+mod export;
+mod history;
+mod key_directory;
+mod kv_store;
+mod kv_store_iter;
+mod options;
+mod replication;
+mod restore;
+mod secondary_index;
+mod segment;
+mod segment_stats;
+mod segments;
+mod snapshot;
+mod store;
+mod time_based_id_generator;
+mod transaction;
+mod util;
+mod version;
+mod watch;
+
+pub use async_kv_store::AsyncKVStore;
+pub use backup::{BackupManifest, BackupSegment};
+pub use changes::{ChangeEvent, ChangeStream};
+pub use codec::{BinaryCodec, JsonCodec, RawCodec, Utf8Codec, ValueCodec};
+pub use entry::key::{KeyComponent, Serializable};
+pub use error::{BitcaskError, Result};
+pub use export::{ExportFormat, ExportRecord};
+pub use history::Revision;
+pub use key_directory::KeyMetadata;
+pub use kv_store::KVStore;
+pub use kv_store_iter::KVStoreIter;
+pub use options::{KVStoreOptions, KeyDirectoryBackend, SyncPolicy};
+pub use replication::{
+    Follower, FollowerInfo, Freshness, POSITION_FILE_NAME, ReplicationLeader, ReplicationStatus,
+};
+pub use restore::{RestorePoint, restore};
+pub use secondary_index::IndexExtractor;
+pub use segment_stats::SegmentStats;
+pub use snapshot::Snapshot;
+pub use transaction::Transaction;
+pub use version::Version;
+pub use watch::{WatchEvent, WatchTarget, Watcher};
@@ -1,39 +1,74 @@
-use crate::entry::key::Serializable;
-use crate::kv_store::KVStore;
-use std::io::Error;
+use bitcask::{
+    AsyncKVStore, ChangeEvent, Follower, Freshness, KVStore, ReplicationLeader, Version,
+};
+use std::env;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::TcpListener;
-use tokio::sync::Mutex;
-
-mod entry;
-mod key_directory;
-mod kv_store;
-mod segment;
-mod segments;
-mod store;
-mod time_based_id_generator;
-mod util;
-
-impl Serializable for String {
-    fn serialize(&self) -> Result<Vec<u8>, Error> {
-        Ok(self.as_bytes().to_vec())
-    }
-    fn deserialize(bytes: Vec<u8>) -> Result<String, Error> {
-        Ok(String::from_utf8(bytes).unwrap())
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::mpsc;
+
+// how long a follower holds a `MINPOS` or `MAXSTALE` read back to catch up before refusing it.
+const BOUNDED_READ_WAIT: Duration = Duration::from_millis(500);
+
+const USAGE: &str = "Usage: bitcask [--dir <path>] [--port <port>] [--follow <host:port>]";
+
+struct Config {
+    dir: String,
+    port: u16,
+    // the leader to replicate from; a follower refuses writes from its own clients.
+    follow: Option<String>,
+}
+
+impl Config {
+    fn parse(args: &[String]) -> Result<Config, String> {
+        let mut config = Config {
+            dir: ".".to_string(),
+            port: 6379,
+            follow: None,
+        };
+
+        for pair in args.chunks(2) {
+            match pair {
+                [flag, dir] if flag == "--dir" => config.dir = dir.clone(),
+                [flag, port] if flag == "--port" => {
+                    config.port = port.parse().map_err(|_| format!("Invalid port {}", port))?
+                }
+                [flag, leader] if flag == "--follow" => config.follow = Some(leader.clone()),
+                _ => return Err(format!("Unexpected arguments {:?}", pair)),
+            }
+        }
+
+        Ok(config)
     }
 }
 
+struct Replication {
+    leader: ReplicationLeader,
+    follower: Option<Follower>,
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let listener = TcpListener::bind("127.0.0.1:6379").await?;
-    println!("Listening on port 6379");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let config = Config::parse(&args).map_err(|message| format!("{}\n{}", message, USAGE))?;
 
-    let dir = ".";
-    let store = Arc::new(Mutex::new(KVStore::<String>::new(
-        dir.to_string(),
-        1024 * 1024,
-    )?));
+    let listener = TcpListener::bind(("127.0.0.1", config.port)).await?;
+    println!("Listening on port {}", config.port);
+
+    let store = AsyncKVStore::new(KVStore::<String>::new(config.dir, 1024 * 1024)?);
+    let follower = match config.follow {
+        Some(leader) => {
+            println!("Following {}", leader);
+            Some(Follower::start(store.blocking().clone(), leader)?)
+        }
+        None => None,
+    };
+    let replication = Arc::new(Replication {
+        leader: ReplicationLeader::new(),
+        follower,
+    });
 
     tokio::spawn(async move {
         tokio::signal::ctrl_c().await.unwrap();
@@ -45,9 +80,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let (socket, addr) = listener.accept().await?;
 
         let store_clone = store.clone();
+        let replication = replication.clone();
 
         tokio::spawn(async move {
-            if let Err(e) = handle_client(socket, store_clone).await {
+            if let Err(e) = handle_client(socket, store_clone, replication, addr.to_string()).await
+            {
                 eprintln!("Error handling client {}: {:?}", addr, e);
             }
         });
@@ -56,7 +93,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 async fn handle_client(
     socket: tokio::net::TcpStream,
-    store: Arc<Mutex<KVStore<String>>>,
+    store: AsyncKVStore<String>,
+    replication: Arc<Replication>,
+    addr: String,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let (reader, mut writer) = socket.into_split();
     let mut reader = BufReader::new(reader);
@@ -74,28 +113,122 @@ async fn handle_client(
 
         let command = Command::parse(&line);
 
+        if let Command::Cdc(position) = command {
+            return stream_changes(&store, position, &mut reader, &mut writer).await;
+        }
+
+        if let Command::Replicate(position) = command {
+            let store = store.blocking().clone();
+            let result = replication
+                .leader
+                .serve(store, position, &mut reader, &mut writer, addr)
+                .await;
+
+            if let Err(e) = result {
+                writer
+                    .write_all(format!("Error {}\n", e).as_bytes())
+                    .await?;
+            }
+            return Ok(());
+        }
+
+        // a leader is always as fresh as can be.
+        let command = match command {
+            Command::Bounded(freshness, command) => {
+                if let Some(follower) = &replication.follower
+                    && let Err(e) = follower.wait_until(freshness, BOUNDED_READ_WAIT).await
+                {
+                    let response = format!(
+                        "Error {}, read from the leader at {}\n",
+                        e,
+                        follower.status().leader
+                    );
+                    writer.write_all(response.as_bytes()).await?;
+                    continue;
+                }
+                *command
+            }
+            command => command,
+        };
+
+        if replication.follower.is_some() && command.is_write() {
+            writer.write_all(b"Error read-only follower\n").await?;
+            continue;
+        }
+
+        let (command, with_version) = match command {
+            Command::Versioned(command) => (*command, true),
+            command => (command, false),
+        };
+
         let response_message = match command {
-            Command::Get(key) => {
-                let store = store.lock().await;
-                if let Some(value) = store.get(key) {
-                    String::from_utf8(value).unwrap()
-                } else {
-                    "Error Key not found".to_string()
+            Command::Get(key) => match store.get(key).await {
+                Ok(Some(value)) => String::from_utf8_lossy(&value).to_string(),
+                Ok(None) => "Error Key not found".to_string(),
+                Err(e) => format!("Error {}", e),
+            },
+            Command::Set(key, value) => match store.put_with_version(key, value.into_bytes()).await
+            {
+                Ok(version) => write_reply("OK", version, with_version),
+                Err(e) => format!("Error {}", e),
+            },
+            Command::Delete(key) => match store.delete_with_version(key).await {
+                Ok(version) => write_reply("OK", version, with_version),
+                Err(e) => format!("Error {}", e),
+            },
+            Command::SetNx(key, value) => {
+                match store.put_if_absent(key, value.into_bytes()).await {
+                    Ok(Some(version)) => write_reply(1, version, with_version),
+                    Ok(None) => "0".to_string(),
+                    Err(e) => format!("Error {}", e),
                 }
             }
-            Command::Set(key, value) => {
-                let mut store = store.lock().await;
-                store
-                    .put(key.clone(), value.clone().into_bytes())
-                    .expect("Error Key store");
-                "OK".to_string()
+            Command::GetVer(key) => match store.get_with_version(key).await {
+                Ok(Some((value, version))) => {
+                    format!("{} {}", version, String::from_utf8_lossy(&value))
+                }
+                Ok(None) => "Error Key not found".to_string(),
+                Err(e) => format!("Error {}", e),
+            },
+            Command::Cas(key, version, value) => {
+                match store
+                    .compare_and_swap(key, version, value.into_bytes())
+                    .await
+                {
+                    Ok(Some(version)) => version.to_string(),
+                    Ok(None) => "Error Version mismatch".to_string(),
+                    Err(e) => format!("Error {}", e),
+                }
             }
-            Command::Delete(key) => {
-                let mut store = store.lock().await;
-                store.delete(key.clone()).expect("Error Key store");
-                "OK".to_string()
+            Command::IncrBy(key, delta) => match store.incr_by_with_version(key, delta).await {
+                Ok((value, version)) => write_reply(value, version, with_version),
+                Err(e) => format!("Error {}", e),
+            },
+            Command::Append(key, suffix) => {
+                match store.append_with_version(key, suffix.into_bytes()).await {
+                    Ok((length, version)) => write_reply(length, version, with_version),
+                    Err(e) => format!("Error {}", e),
+                }
+            }
+            Command::Exists(key) => {
+                if store.contains(key) {
+                    "1".to_string()
+                } else {
+                    "0".to_string()
+                }
             }
-            Command::Unknown => "Unknown Command".to_string(),
+            Command::DbSize => store.len().to_string(),
+            Command::StrLen(key) => store
+                .stat(key)
+                .map(|metadata| metadata.value_size)
+                .unwrap_or(0)
+                .to_string(),
+            Command::Replication => replication_status(&store, &replication).await,
+            Command::Cdc(_)
+            | Command::Replicate(_)
+            | Command::Bounded(..)
+            | Command::Versioned(_)
+            | Command::Unknown => "Unknown Command".to_string(),
         };
 
         let response = response_message + "\n";
@@ -105,11 +238,164 @@ async fn handle_client(
     Ok(())
 }
 
+// CDC turns the connection into a one-way stream of changes, one line per entry:
+// `<position> <next_position> <timestamp> PUT <key> <value>` or `... DEL <key>`. The change
+// stream blocks, so it runs on the blocking pool and hands lines over a channel until the
+// client hangs up.
+async fn stream_changes(
+    store: &AsyncKVStore<String>,
+    position: Version,
+    reader: &mut BufReader<OwnedReadHalf>,
+    writer: &mut OwnedWriteHalf,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut changes = match store.changes_from(position).await {
+        Ok(changes) => changes,
+        Err(e) => {
+            writer
+                .write_all(format!("Error {}\n", e).as_bytes())
+                .await?;
+            return Ok(());
+        }
+    };
+    let (sender, mut receiver) = mpsc::channel::<String>(1024);
+
+    let producer = tokio::task::spawn_blocking(move || {
+        while !sender.is_closed() {
+            let line = match changes.next_timeout(Duration::from_secs(1)) {
+                Ok(Some(event)) => format_change(&event),
+                Ok(None) => continue,
+                Err(e) => {
+                    let _ = sender.blocking_send(format!("Error {}", e));
+                    break;
+                }
+            };
+
+            if sender.blocking_send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut ignored = String::new();
+    loop {
+        tokio::select! {
+            line = receiver.recv() => match line {
+                Some(line) => writer.write_all((line + "\n").as_bytes()).await?,
+                None => break,
+            },
+            read = reader.read_line(&mut ignored) => {
+                if read? == 0 {
+                    break;
+                }
+                ignored.clear();
+            }
+        }
+    }
+
+    drop(receiver);
+    producer.await?;
+
+    Ok(())
+}
+
+// `follower leader=<addr> connected=<0|1> applied=<pos> leader_position=<pos> lag_ms=<n>
+// staleness_ms=<n|->` on a follower, `leader followers=<n>` and
+// `; <addr> acknowledged=<pos> lag_ms=<n>` per follower on a leader.
+async fn replication_status(
+    store: &AsyncKVStore<String>,
+    replication: &Arc<Replication>,
+) -> String {
+    if let Some(follower) = &replication.follower {
+        let status = follower.status();
+        let mut response = format!(
+            "follower leader={} connected={} applied={} leader_position={} lag_ms={} staleness_ms={}",
+            status.leader,
+            status.connected as u8,
+            status.applied,
+            status.leader_position,
+            status.lag.as_millis(),
+            status
+                .staleness
+                .map_or("-".to_string(), |staleness| staleness
+                    .as_millis()
+                    .to_string())
+        );
+        if let Some(error) = status.last_error {
+            response.push_str(&format!(" last_error={}", error));
+        }
+        return response;
+    }
+
+    let followers = {
+        let store = store.blocking().clone();
+        let replication = replication.clone();
+        tokio::task::spawn_blocking(move || replication.leader.followers(&store)).await
+    };
+
+    match followers {
+        Ok(Ok(followers)) => {
+            let mut response = format!("leader followers={}", followers.len());
+            for follower in followers {
+                response.push_str(&format!(
+                    "; {} acknowledged={} lag_ms={}",
+                    follower.address,
+                    follower.acknowledged,
+                    follower.lag.as_millis()
+                ));
+            }
+            response
+        }
+        Ok(Err(e)) => format!("Error {}", e),
+        Err(e) => format!("Error {}", e),
+    }
+}
+
+// writes keep their plain replies unless `WITHVER` asks for the version they wrote at.
+fn write_reply(reply: impl ToString, version: Version, with_version: bool) -> String {
+    if with_version {
+        format!("{} {}", reply.to_string(), version)
+    } else {
+        reply.to_string()
+    }
+}
+
+fn format_change(event: &ChangeEvent<String>) -> String {
+    match &event.value {
+        Some(value) => format!(
+            "{} {} {} PUT {} {}",
+            event.position,
+            event.next_position,
+            event.timestamp,
+            event.key,
+            String::from_utf8_lossy(value)
+        ),
+        None => format!(
+            "{} {} {} DEL {}",
+            event.position, event.next_position, event.timestamp, event.key
+        ),
+    }
+}
+
 #[derive(Debug)]
 pub enum Command {
     Get(String),
     Set(String, String),
     Delete(String),
+    SetNx(String, String),
+    GetVer(String),
+    Cas(String, Version, String),
+    IncrBy(String, i64),
+    Append(String, String),
+    Exists(String),
+    DbSize,
+    StrLen(String),
+    Cdc(Version),
+    Replicate(Version),
+    Replication,
+    // a read that a follower only serves once it is as fresh as asked for.
+    Bounded(Freshness, Box<Command>),
+    // a write that answers with the version it wrote at as well.
+    Versioned(Box<Command>),
     Unknown,
 }
 
@@ -121,7 +407,85 @@ impl Command {
             ["GET", key] => Command::Get(key.to_string()),
             ["SET", key, value] => Command::Set(key.to_string(), value.to_string()),
             ["DELETE", key] => Command::Delete(key.to_string()),
+            ["SETNX", key, value] => Command::SetNx(key.to_string(), value.to_string()),
+            ["GETVER", key] => Command::GetVer(key.to_string()),
+            ["CAS", key, rest] => match rest.split_once(' ') {
+                Some((version, value)) => match version.parse() {
+                    Ok(version) => Command::Cas(key.to_string(), version, value.to_string()),
+                    Err(_) => Command::Unknown,
+                },
+                None => Command::Unknown,
+            },
+            ["INCR", key] => Command::IncrBy(key.to_string(), 1),
+            ["DECR", key] => Command::IncrBy(key.to_string(), -1),
+            ["INCRBY", key, delta] => match delta.parse() {
+                Ok(delta) => Command::IncrBy(key.to_string(), delta),
+                Err(_) => Command::Unknown,
+            },
+            ["APPEND", key, suffix] => Command::Append(key.to_string(), suffix.to_string()),
+            ["EXISTS", key] => Command::Exists(key.to_string()),
+            ["DBSIZE"] => Command::DbSize,
+            ["STRLEN", key] => Command::StrLen(key.to_string()),
+            ["CDC"] => Command::Cdc(Version {
+                file_id: 0,
+                offset: 0,
+            }),
+            ["CDC", position] => match position.parse() {
+                Ok(position) => Command::Cdc(position),
+                Err(_) => Command::Unknown,
+            },
+            ["REPLICATE", position] => match position.parse() {
+                Ok(position) => Command::Replicate(position),
+                Err(_) => Command::Unknown,
+            },
+            ["REPLICATION"] => Command::Replication,
+            ["MINPOS", position, read] => match position.parse() {
+                Ok(position) => Command::bounded(Freshness::MinPosition(position), read),
+                Err(_) => Command::Unknown,
+            },
+            ["MAXSTALE", millis, read] => match millis.parse() {
+                Ok(millis) => {
+                    Command::bounded(Freshness::MaxStaleness(Duration::from_millis(millis)), read)
+                }
+                Err(_) => Command::Unknown,
+            },
+            ["WITHVER", command, rest] => Command::versioned(&format!("{} {}", command, rest)),
             _ => Command::Unknown,
         }
     }
+
+    fn versioned(write: &str) -> Command {
+        match Command::parse(write) {
+            command @ (Command::Set(..)
+            | Command::Delete(_)
+            | Command::SetNx(..)
+            | Command::IncrBy(..)
+            | Command::Append(..)) => Command::Versioned(Box::new(command)),
+            _ => Command::Unknown,
+        }
+    }
+
+    fn bounded(freshness: Freshness, read: &str) -> Command {
+        match Command::parse(read) {
+            command @ (Command::Get(_)
+            | Command::GetVer(_)
+            | Command::Exists(_)
+            | Command::DbSize
+            | Command::StrLen(_)) => Command::Bounded(freshness, Box::new(command)),
+            _ => Command::Unknown,
+        }
+    }
+
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::Set(..)
+                | Command::Delete(_)
+                | Command::SetNx(..)
+                | Command::Cas(..)
+                | Command::IncrBy(..)
+                | Command::Append(..)
+                | Command::Versioned(_)
+        )
+    }
 }
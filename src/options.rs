@@ -0,0 +1,292 @@
+use crate::codec::ValueCodec;
+use crate::entry;
+use crate::error::{BitcaskError, Result};
+use crate::kv_store::KVStore;
+use crate::segment::{SEGMENT_FILE_PREFIX, SEGMENT_FILE_SUFFIX};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+pub(crate) const DEFAULT_MAX_SEGMENT_SIZE: u32 = 1024 * 1024;
+const DEFAULT_WATCH_BUFFER_SIZE: usize = 1024;
+
+/// When appended entries are forced to disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Leave flushing to the operating system; segments are synced when they roll over.
+    #[default]
+    Never,
+    /// Sync the active segment after every write.
+    Always,
+}
+
+/// How the in-memory key directory is laid out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyDirectoryBackend {
+    /// Hash maps split over independently locked shards, best for concurrent point lookups.
+    #[default]
+    Hash,
+    /// A single ordered map, so `KVStore::keys` yields keys in order.
+    Ordered,
+}
+
+/// Options for opening a `KVStore`, in the style of `std::fs::OpenOptions`.
+///
+/// ```
+/// use bitcask::{KVStore, KVStoreOptions, SyncPolicy};
+///
+/// let dir = tempfile::tempdir().unwrap();
+/// let store: KVStore<String> = KVStoreOptions::new()
+///     .max_segment_size(64 * 1024)
+///     .sync_policy(SyncPolicy::Always)
+///     .open(dir.path())
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct KVStoreOptions {
+    pub(crate) max_segment_size: u32,
+    pub(crate) sync_policy: SyncPolicy,
+    pub(crate) create_if_missing: bool,
+    pub(crate) error_if_exists: bool,
+    pub(crate) read_only: bool,
+    pub(crate) key_directory_backend: KeyDirectoryBackend,
+    pub(crate) watch_buffer_size: usize,
+    pub(crate) history_retention: Duration,
+}
+
+impl Default for KVStoreOptions {
+    fn default() -> Self {
+        KVStoreOptions {
+            max_segment_size: DEFAULT_MAX_SEGMENT_SIZE,
+            sync_policy: SyncPolicy::default(),
+            create_if_missing: false,
+            error_if_exists: false,
+            read_only: false,
+            key_directory_backend: KeyDirectoryBackend::default(),
+            watch_buffer_size: DEFAULT_WATCH_BUFFER_SIZE,
+            history_retention: Duration::ZERO,
+        }
+    }
+}
+
+impl KVStoreOptions {
+    pub fn new() -> KVStoreOptions {
+        KVStoreOptions::default()
+    }
+
+    /// Size in bytes after which the active segment is rolled over.
+    pub fn max_segment_size(&mut self, max_segment_size: u32) -> &mut Self {
+        self.max_segment_size = max_segment_size;
+        self
+    }
+
+    pub fn sync_policy(&mut self, sync_policy: SyncPolicy) -> &mut Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// Create the directory if it does not exist yet.
+    pub fn create_if_missing(&mut self, create_if_missing: bool) -> &mut Self {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    /// Fail if the directory already holds segment files.
+    pub fn error_if_exists(&mut self, error_if_exists: bool) -> &mut Self {
+        self.error_if_exists = error_if_exists;
+        self
+    }
+
+    /// Open the existing segments for reading only; writes are rejected.
+    pub fn read_only(&mut self, read_only: bool) -> &mut Self {
+        self.read_only = read_only;
+        self
+    }
+
+    pub fn key_directory_backend(&mut self, backend: KeyDirectoryBackend) -> &mut Self {
+        self.key_directory_backend = backend;
+        self
+    }
+
+    /// Events each watcher can hold before it starts missing them.
+    pub fn watch_buffer_size(&mut self, watch_buffer_size: usize) -> &mut Self {
+        self.watch_buffer_size = watch_buffer_size;
+        self
+    }
+
+    /// How far back `KVStore::get_at` must keep working. Compaction leaves segments alone
+    /// while they hold entries that were superseded within this window. The default of zero
+    /// lets compaction drop all history.
+    pub fn history_retention(&mut self, history_retention: Duration) -> &mut Self {
+        self.history_retention = history_retention;
+        self
+    }
+
+    pub fn open<T: entry::key::Serializable, V, C: ValueCodec<V>>(
+        &self,
+        directory: impl AsRef<Path>,
+    ) -> Result<KVStore<T, V, C>> {
+        let directory = directory.as_ref();
+        self.validate()?;
+        self.prepare_directory(directory)?;
+
+        let directory = directory.to_str().ok_or_else(|| {
+            BitcaskError::InvalidOptions(format!("directory {:?} is not valid UTF-8", directory))
+        })?;
+
+        KVStore::open(directory.to_string(), self)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.max_segment_size == 0 {
+            return Err(BitcaskError::InvalidOptions(
+                "max_segment_size must be greater than zero".to_string(),
+            ));
+        }
+
+        if self.watch_buffer_size == 0 {
+            return Err(BitcaskError::InvalidOptions(
+                "watch_buffer_size must be greater than zero".to_string(),
+            ));
+        }
+
+        if self.read_only && self.create_if_missing {
+            return Err(BitcaskError::InvalidOptions("create_if_missing cannot be combined with read_only, a read-only store must already exist".to_string()));
+        }
+
+        if self.read_only && self.error_if_exists {
+            return Err(BitcaskError::InvalidOptions("error_if_exists cannot be combined with read_only, a read-only store must already exist".to_string()));
+        }
+
+        if self.read_only && self.sync_policy == SyncPolicy::Always {
+            return Err(BitcaskError::InvalidOptions(
+                "sync_policy Always cannot be combined with read_only, nothing is ever written"
+                    .to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn prepare_directory(&self, directory: &Path) -> Result<()> {
+        if directory.as_os_str().is_empty() {
+            return Err(BitcaskError::InvalidOptions(
+                "directory must not be empty".to_string(),
+            ));
+        }
+
+        if !directory.exists() {
+            if !self.create_if_missing {
+                return Err(BitcaskError::NotFound(format!(
+                    "directory {} (set create_if_missing to create it)",
+                    directory.display()
+                )));
+            }
+
+            return Ok(fs::create_dir_all(directory)?);
+        }
+
+        if !directory.is_dir() {
+            return Err(BitcaskError::InvalidOptions(format!(
+                "{} is not a directory",
+                directory.display()
+            )));
+        }
+
+        if self.error_if_exists && Self::contains_segments(directory)? {
+            return Err(BitcaskError::AlreadyExists(directory.display().to_string()));
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn contains_segments(directory: &Path) -> Result<bool> {
+        let suffix = format!("_{}.{}", SEGMENT_FILE_PREFIX, SEGMENT_FILE_SUFFIX);
+
+        for entry in fs::read_dir(directory)? {
+            if entry?.file_name().to_string_lossy().ends_with(&suffix) {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::{BitcaskError, Result};
+    use crate::kv_store::KVStore;
+    use crate::options::{KVStoreOptions, SyncPolicy};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_missing_directory_requires_create_if_missing() {
+        let dir = tempdir().unwrap();
+        let missing = dir.path().join("missing");
+
+        let result: Result<KVStore<String>> = KVStoreOptions::new().open(&missing);
+        assert!(matches!(result, Err(BitcaskError::NotFound(_))));
+
+        let kv_store: KVStore<String> = KVStoreOptions::new()
+            .create_if_missing(true)
+            .open(&missing)
+            .unwrap();
+        kv_store.put("key".to_string(), vec![1]).unwrap();
+
+        assert!(missing.is_dir());
+    }
+
+    #[test]
+    fn test_error_if_exists() {
+        let dir = tempdir().unwrap();
+
+        let kv_store: KVStore<String> = KVStoreOptions::new()
+            .error_if_exists(true)
+            .open(dir.path())
+            .unwrap();
+        drop(kv_store);
+
+        let result: Result<KVStore<String>> =
+            KVStoreOptions::new().error_if_exists(true).open(dir.path());
+        assert!(matches!(result, Err(BitcaskError::AlreadyExists(_))));
+    }
+
+    #[test]
+    fn test_invalid_combinations() {
+        let dir = tempdir().unwrap();
+
+        let results: [Result<KVStore<String>>; 4] = [
+            KVStoreOptions::new().max_segment_size(0).open(dir.path()),
+            KVStoreOptions::new()
+                .read_only(true)
+                .create_if_missing(true)
+                .open(dir.path()),
+            KVStoreOptions::new()
+                .read_only(true)
+                .error_if_exists(true)
+                .open(dir.path()),
+            KVStoreOptions::new()
+                .read_only(true)
+                .sync_policy(SyncPolicy::Always)
+                .open(dir.path()),
+        ];
+
+        for result in results {
+            assert!(matches!(result, Err(BitcaskError::InvalidOptions(_))));
+        }
+    }
+
+    #[test]
+    fn test_sync_always_round_trip() {
+        let dir = tempdir().unwrap();
+
+        let kv_store: KVStore<String> = KVStoreOptions::new()
+            .sync_policy(SyncPolicy::Always)
+            .open(dir.path())
+            .unwrap();
+        kv_store.put("key".to_string(), vec![1, 2]).unwrap();
+
+        assert_eq!(kv_store.get("key".to_string()).unwrap(), Some(vec![1, 2]));
+    }
+}
@@ -0,0 +1,1095 @@
+use crate::changes::ChangeEvent;
+use crate::codec::ValueCodec;
+use crate::entry;
+use crate::entry::Entry;
+use crate::error::{BitcaskError, Result};
+use crate::kv_store::KVStore;
+use crate::options::KVStoreOptions;
+use crate::segment::Segment;
+use crate::version::Version;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::{Notify, mpsc};
+use tokio::task::JoinHandle;
+
+/// Where a follower keeps the leader position it has applied up to, in its own directory.
+pub const POSITION_FILE_NAME: &str = "LEADER_POSITION";
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const SNAPSHOT_CHUNK_SIZE: u64 = 48 * 1024;
+// where a follower collects the segments of a snapshot before loading them.
+const SNAPSHOT_DIRECTORY_NAME: &str = "snapshot";
+const START: Version = Version {
+    file_id: 0,
+    offset: 0,
+};
+
+// The protocol is line based, so it can share the server's port. A follower opens with
+// `REPLICATE <position>` and the leader answers with its log from there on:
+//
+//   ENTRY <position> <next_position> <timestamp> <continues_group> PUT <key> <value>
+//   ENTRY <position> <next_position> <timestamp> <continues_group> DEL <key>
+//   HEAD <log_position>
+//
+// keys and values in base64, with a `HEAD` at least every `HEARTBEAT_INTERVAL`. The follower
+// answers each `HEAD` with `ACK <position>` once it has applied everything before it.
+//
+// A follower that has nothing yet, or whose position is in a segment compaction has removed,
+// is first sent a snapshot of the sealed segments, and the log follows from where the
+// snapshot ends:
+//
+//   SNAPSHOT <segment_count>
+//   SEGMENT <file_id> <size>, then `DATA <bytes>` lines in base64 until `size` is reached
+//   END <position>
+
+/// A follower as seen from the leader, returned by `ReplicationLeader::followers`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FollowerInfo {
+    pub address: String,
+    /// The position the follower last reported to have applied everything up to.
+    pub acknowledged: Version,
+    /// How long the follower has been behind the end of the log, zero while it is caught up.
+    pub lag: Duration,
+}
+
+/// The followers replicating from a store, fed by `serve`.
+#[derive(Default)]
+pub struct ReplicationLeader {
+    followers: Mutex<BTreeMap<u64, FollowerState>>,
+    next_id: AtomicU64,
+}
+
+struct FollowerState {
+    address: String,
+    acknowledged: Version,
+    caught_up_at: Instant,
+}
+
+// forgets a follower however its connection ends.
+struct Registration<'a> {
+    leader: &'a ReplicationLeader,
+    id: u64,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.leader.followers.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl ReplicationLeader {
+    pub fn new() -> ReplicationLeader {
+        ReplicationLeader::default()
+    }
+
+    /// Streams `store`'s log from `position` to a follower that sent `REPLICATE <position>`,
+    /// until it hangs up. The log is opened and read on tokio's blocking thread pool.
+    pub async fn serve<T, V, C>(
+        &self,
+        store: Arc<KVStore<T, V, C>>,
+        position: Version,
+        reader: &mut (impl AsyncBufRead + Unpin),
+        writer: &mut (impl AsyncWrite + Unpin),
+        address: String,
+    ) -> Result<()>
+    where
+        T: entry::key::Serializable + 'static,
+        V: 'static,
+        C: ValueCodec<V> + 'static,
+    {
+        let opened = {
+            let store = store.clone();
+            tokio::task::spawn_blocking(move || match store.changes_from(position) {
+                Ok(changes) if position != START => Ok((changes, None)),
+                Ok(_) | Err(BitcaskError::NotFound(_)) => {
+                    let (segments, pin) = store.seal_and_pin()?;
+                    let end = match segments.last() {
+                        Some(segment) => Version {
+                            file_id: segment.file_id,
+                            offset: segment.store.len()?,
+                        },
+                        None => START,
+                    };
+                    // the stream pins the newest sealed segment, and with it every newer one.
+                    Ok((store.changes_from(end)?, Some((segments, end, pin))))
+                }
+                Err(error) => Err(error),
+            })
+        };
+        let (mut changes, snapshot) = opened.await.map_err(io::Error::other)??;
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.followers.lock().unwrap().insert(
+            id,
+            FollowerState {
+                address,
+                acknowledged: position,
+                caught_up_at: Instant::now(),
+            },
+        );
+        let _registration = Registration { leader: self, id };
+
+        let (sender, mut receiver) = mpsc::channel::<String>(1024);
+        let producer = {
+            let store = store.clone();
+            tokio::task::spawn_blocking(move || {
+                if let Some((segments, end, _pin)) = snapshot
+                    && let Err(error) = send_snapshot(&sender, &segments, end)
+                {
+                    let _ = sender.blocking_send(format!("Error {}", error));
+                    return;
+                }
+
+                let mut last_heartbeat: Option<Instant> = None;
+
+                while !sender.is_closed() {
+                    let until_heartbeat = last_heartbeat
+                        .map(|at| HEARTBEAT_INTERVAL.saturating_sub(at.elapsed()))
+                        .unwrap_or_default();
+                    let line = if until_heartbeat.is_zero() {
+                        last_heartbeat = Some(Instant::now());
+                        store
+                            .log_position()
+                            .map(|position| format!("HEAD {}", position))
+                    } else {
+                        match changes.next_timeout(until_heartbeat) {
+                            Ok(Some(event)) => format_entry(&event),
+                            Ok(None) => continue,
+                            Err(error) => Err(error),
+                        }
+                    };
+                    let line = line.unwrap_or_else(|error| format!("Error {}", error));
+                    let failed = line.starts_with("Error");
+
+                    if sender.blocking_send(line).is_err() || failed {
+                        break;
+                    }
+                }
+            })
+        };
+
+        let mut line = String::new();
+        let result = loop {
+            tokio::select! {
+                message = receiver.recv() => match message {
+                    Some(message) => writer.write_all((message + "\n").as_bytes()).await?,
+                    None => break Ok(()),
+                },
+                read = reader.read_line(&mut line) => {
+                    if read? == 0 {
+                        break Ok(());
+                    }
+
+                    match line.trim().strip_prefix("ACK ").map(str::parse) {
+                        Some(Ok(acknowledged)) => {
+                            let store = store.clone();
+                            let log_position =
+                                tokio::task::spawn_blocking(move || store.log_position())
+                                    .await
+                                    .map_err(io::Error::other)??;
+                            self.acknowledge(id, acknowledged, log_position)
+                        }
+                        _ => break Err(invalid_message(&line)),
+                    }
+                    line.clear();
+                }
+            }
+        };
+
+        drop(receiver);
+        producer.await.map_err(io::Error::other)?;
+
+        result
+    }
+
+    fn acknowledge(&self, id: u64, acknowledged: Version, log_position: Version) {
+        if let Some(follower) = self.followers.lock().unwrap().get_mut(&id) {
+            follower.acknowledged = acknowledged;
+
+            if acknowledged >= log_position {
+                follower.caught_up_at = Instant::now();
+            }
+        }
+    }
+
+    /// Every follower connected right now, with its lag behind `store`'s log.
+    pub fn followers<T, V, C>(&self, store: &KVStore<T, V, C>) -> Result<Vec<FollowerInfo>>
+    where
+        T: entry::key::Serializable,
+        C: ValueCodec<V>,
+    {
+        let log_position = store.log_position()?;
+
+        Ok(self
+            .followers
+            .lock()
+            .unwrap()
+            .values()
+            .map(|follower| FollowerInfo {
+                address: follower.address.clone(),
+                acknowledged: follower.acknowledged,
+                lag: lag(follower.acknowledged, log_position, follower.caught_up_at),
+            })
+            .collect())
+    }
+}
+
+/// How far a follower is behind its leader, returned by `Follower::status`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplicationStatus {
+    pub leader: String,
+    pub connected: bool,
+    /// The leader log position up to which entries have been applied.
+    pub applied: Version,
+    /// The end of the leader's log as of its last heartbeat.
+    pub leader_position: Version,
+    /// How long the follower has been behind the leader, zero while it is caught up.
+    pub lag: Duration,
+    /// How long ago the follower last had every entry the leader had written, `None` until
+    /// it has heard from the leader and caught up once.
+    pub staleness: Option<Duration>,
+    /// Why the last connection to the leader ended, `None` if none has failed yet.
+    pub last_error: Option<String>,
+}
+
+/// How fresh a follower must be to serve a read, see `Follower::wait_until`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Freshness {
+    /// Everything the leader had written this long ago has been applied. Staleness is
+    /// measured from the leader's heartbeats, which come every second, so a smaller bound can
+    /// only be met right after one.
+    MaxStaleness(Duration),
+    /// The entry at this leader position, such as the version a write returned there, has
+    /// been applied along with everything before it.
+    MinPosition(Version),
+}
+
+struct Progress {
+    connected: bool,
+    applied: Version,
+    leader_position: Version,
+    caught_up_at: Instant,
+    // leader positions heard in heartbeats that haven't been applied yet, with when.
+    heads: VecDeque<(Version, Instant)>,
+    confirmed_at: Option<Instant>,
+    last_error: Option<String>,
+}
+
+impl Progress {
+    // moves `applied` forward, past the leader position last heard of if need be.
+    fn advance(&mut self, applied: Version) {
+        self.applied = applied;
+        self.leader_position = self.leader_position.max(applied);
+        self.confirm();
+    }
+
+    fn heard_head(&mut self, leader_position: Version) {
+        self.leader_position = leader_position;
+        self.heads.push_back((leader_position, Instant::now()));
+        self.confirm();
+    }
+
+    fn confirm(&mut self) {
+        if self.applied >= self.leader_position {
+            self.caught_up_at = Instant::now();
+        }
+
+        while let Some((position, heard_at)) = self.heads.front().copied()
+            && position <= self.applied
+        {
+            self.confirmed_at = Some(heard_at);
+            self.heads.pop_front();
+        }
+    }
+
+    fn unmet(&self, freshness: Freshness) -> Option<String> {
+        match freshness {
+            Freshness::MinPosition(position) if self.applied <= position => Some(format!(
+                "applied up to {}, not past {} yet",
+                self.applied, position
+            )),
+            Freshness::MaxStaleness(bound) => match self.confirmed_at {
+                Some(at) if at.elapsed() <= bound => None,
+                Some(at) => Some(format!(
+                    "last caught up with the leader {}ms ago",
+                    at.elapsed().as_millis()
+                )),
+                None => Some("not caught up with the leader yet".to_string()),
+            },
+            Freshness::MinPosition(_) => None,
+        }
+    }
+}
+
+struct Replica {
+    progress: Mutex<Progress>,
+    changed: Notify,
+}
+
+impl Replica {
+    fn update(&self, update: impl FnOnce(&mut Progress)) {
+        update(&mut self.progress.lock().unwrap());
+        self.changed.notify_waiters();
+    }
+}
+
+/// Keeps a store in step with a leader's log: every entry appended on the leader is written
+/// to the follower's own segments with the leader's timestamp, a transaction as one group,
+/// and the follower's reads see it once it is applied.
+///
+/// The position reached is saved in the store's directory on every heartbeat, once the
+/// entries before it are synced to disk, so a restarted follower picks up from there, even
+/// after a crash; entries applied after the last save are applied again,
+/// which leaves the same state behind. A follower with no position yet, or one whose position
+/// the leader has compacted away, is first sent the leader's sealed segments; it loads their
+/// live keys, deletes every key of its own they don't have, and then tails the log from where
+/// the segments end. Reads see the load happen key by key.
+///
+/// A dropped connection is retried every second until the `Follower` is dropped, and the
+/// error that ended it is reported by `status`. Writes made
+/// to the store other than through the follower are not sent anywhere and may be overwritten
+/// by the leader's.
+pub struct Follower {
+    leader: String,
+    replica: Arc<Replica>,
+    task: JoinHandle<()>,
+}
+
+impl Follower {
+    /// Starts following `leader`, a `host:port` address, on the current tokio runtime.
+    pub fn start<T, V, C>(store: Arc<KVStore<T, V, C>>, leader: String) -> Result<Follower>
+    where
+        T: entry::key::Serializable + 'static,
+        V: Send + 'static,
+        C: ValueCodec<V> + 'static,
+    {
+        let applied = read_position(store.path())?;
+        let replica = Arc::new(Replica {
+            progress: Mutex::new(Progress {
+                connected: false,
+                applied,
+                leader_position: applied,
+                caught_up_at: Instant::now(),
+                heads: VecDeque::new(),
+                confirmed_at: None,
+                last_error: None,
+            }),
+            changed: Notify::new(),
+        });
+
+        let task = tokio::spawn({
+            let leader = leader.clone();
+            let replica = replica.clone();
+            async move {
+                loop {
+                    let result = follow(&store, &leader, &replica).await;
+
+                    replica.update(|progress| {
+                        progress.connected = false;
+                        if let Err(error) = result {
+                            progress.last_error = Some(error.to_string());
+                        }
+                    });
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        });
+
+        Ok(Follower {
+            leader,
+            replica,
+            task,
+        })
+    }
+
+    pub fn status(&self) -> ReplicationStatus {
+        let progress = self.replica.progress.lock().unwrap();
+
+        ReplicationStatus {
+            leader: self.leader.clone(),
+            connected: progress.connected,
+            applied: progress.applied,
+            leader_position: progress.leader_position,
+            lag: lag(
+                progress.applied,
+                progress.leader_position,
+                progress.caught_up_at,
+            ),
+            staleness: progress.confirmed_at.map(|at| at.elapsed()),
+            last_error: progress.last_error.clone(),
+        }
+    }
+
+    /// Waits up to `timeout` for the follower to be as fresh as `freshness` asks, after which
+    /// reads from its store are. Fails with `BitcaskError::Stale` if it isn't by then.
+    pub async fn wait_until(&self, freshness: Freshness, timeout: Duration) -> Result<()> {
+        let deadline = tokio::time::Instant::now() + timeout;
+
+        loop {
+            // registered before checking, so an update in between still wakes us up.
+            let changed = self.replica.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+
+            let Some(unmet) = self.replica.progress.lock().unwrap().unmet(freshness) else {
+                return Ok(());
+            };
+
+            if tokio::time::timeout_at(deadline, changed).await.is_err() {
+                return Err(BitcaskError::Stale(unmet));
+            }
+        }
+    }
+}
+
+impl Drop for Follower {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+// runs one connection to the leader, returning only once it fails.
+async fn follow<T, V, C>(
+    store: &Arc<KVStore<T, V, C>>,
+    leader: &str,
+    replica: &Replica,
+) -> Result<()>
+where
+    T: entry::key::Serializable + 'static,
+    V: Send + 'static,
+    C: ValueCodec<V> + 'static,
+{
+    let (reader, mut writer) = TcpStream::connect(leader).await?.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut applied = replica.progress.lock().unwrap().applied;
+    writer
+        .write_all(format!("REPLICATE {}\n", applied).as_bytes())
+        .await?;
+    replica.update(|progress| progress.connected = true);
+
+    let mut group = Vec::new();
+    let mut line = String::new();
+
+    loop {
+        let message = read_message(&mut reader, &mut line).await?;
+
+        if let Some(leader_position) = message.strip_prefix("HEAD ") {
+            let leader_position = leader_position.parse()?;
+            replica.update(|progress| progress.heard_head(leader_position));
+            save_position(store, applied).await?;
+            writer
+                .write_all(format!("ACK {}\n", applied).as_bytes())
+                .await?;
+            continue;
+        }
+
+        if let Some(segment_count) = message.strip_prefix("SNAPSHOT ") {
+            let segment_count = segment_count
+                .parse()
+                .map_err(|_| invalid_message(message))?;
+            applied = receive_snapshot(store, &mut reader, segment_count).await?;
+            save_position(store, applied).await?;
+            replica.update(|progress| progress.advance(applied));
+            continue;
+        }
+
+        let (entry, next_position, continues_group) = parse_entry(message)?;
+        group.push(entry);
+
+        if continues_group {
+            continue;
+        }
+
+        let entries = std::mem::take(&mut group);
+        let store = store.clone();
+        tokio::task::spawn_blocking(move || store.apply_replicated(entries))
+            .await
+            .map_err(io::Error::other)??;
+
+        applied = next_position;
+        replica.update(|progress| progress.advance(applied));
+    }
+}
+
+// the next line from the leader without its line break, failing on an `Error` from it.
+async fn read_message<'l>(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    line: &'l mut String,
+) -> Result<&'l str> {
+    line.clear();
+
+    if reader.read_line(line).await? == 0 {
+        return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+
+    let message = line.trim_end_matches(['\r', '\n']);
+
+    match message.strip_prefix("Error ") {
+        Some(reason) => Err(io::Error::other(reason.to_string()).into()),
+        None => Ok(message),
+    }
+}
+
+// writes the segments of a snapshot to a directory of their own, then loads them into
+// `store`. Returns the leader position the snapshot ends at.
+async fn receive_snapshot<T, V, C>(
+    store: &Arc<KVStore<T, V, C>>,
+    reader: &mut (impl AsyncBufRead + Unpin),
+    segment_count: usize,
+) -> Result<Version>
+where
+    T: entry::key::Serializable + 'static,
+    V: Send + 'static,
+    C: ValueCodec<V> + 'static,
+{
+    let directory = Path::new(store.path()).join(SNAPSHOT_DIRECTORY_NAME);
+    if tokio::fs::try_exists(&directory).await? {
+        tokio::fs::remove_dir_all(&directory).await?;
+    }
+    tokio::fs::create_dir_all(&directory).await?;
+
+    let mut line = String::new();
+
+    for _ in 0..segment_count {
+        let message = read_message(reader, &mut line).await?;
+        let parts: Vec<&str> = message.split(' ').collect();
+        let ["SEGMENT", file_id, size] = parts.as_slice() else {
+            return Err(invalid_message(message));
+        };
+        let file_id: u64 = file_id.parse().map_err(|_| invalid_message(message))?;
+        let size: u64 = size.parse().map_err(|_| invalid_message(message))?;
+
+        let mut file = tokio::fs::File::create(directory.join(Segment::file_name(file_id))).await?;
+        let mut written = 0;
+
+        while written < size {
+            let message = read_message(reader, &mut line).await?;
+            let chunk = match message.strip_prefix("DATA ") {
+                Some(chunk) => decode(chunk, message)?,
+                None => return Err(invalid_message(message)),
+            };
+            file.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+        }
+
+        file.sync_all().await?;
+    }
+
+    let message = read_message(reader, &mut line).await?;
+    let end = match message.strip_prefix("END ") {
+        Some(end) => end.parse()?,
+        None => return Err(invalid_message(message)),
+    };
+
+    let store = store.clone();
+    tokio::task::spawn_blocking(move || load_snapshot(&store, &directory))
+        .await
+        .map_err(io::Error::other)??;
+
+    Ok(end)
+}
+
+// leaves `store` with the live keys of the segments in `directory` and no others, each with
+// the timestamp it was written with on the leader.
+fn load_snapshot<T, V, C>(store: &KVStore<T, V, C>, directory: &Path) -> Result<()>
+where
+    T: entry::key::Serializable,
+    C: ValueCodec<V>,
+{
+    let snapshot: KVStore<T> = KVStoreOptions::new().read_only(true).open(directory)?;
+
+    for key in store.keys() {
+        if !snapshot.contains(key.clone()) {
+            store.apply_replicated(vec![Entry::new_deleted_entry(key)])?;
+        }
+    }
+
+    for key in snapshot.keys() {
+        let (Some(value), Some(metadata)) =
+            (snapshot.get(key.clone())?, snapshot.stat(key.clone()))
+        else {
+            continue;
+        };
+        store.apply_replicated(vec![Entry::new_preserving_timestamp(
+            key,
+            value,
+            metadata.timestamp,
+        )])?;
+    }
+
+    drop(snapshot);
+    fs::remove_dir_all(directory)?;
+
+    Ok(())
+}
+
+// a send only fails once the connection is gone, which the caller notices by itself.
+fn send_snapshot(
+    sender: &mpsc::Sender<String>,
+    segments: &[Arc<Segment>],
+    end: Version,
+) -> Result<()> {
+    let _ = sender.blocking_send(format!("SNAPSHOT {}", segments.len()));
+
+    for segment in segments {
+        let size = segment.store.len()?;
+        let _ = sender.blocking_send(format!("SEGMENT {} {}", segment.file_id, size));
+        let mut offset = 0;
+
+        while offset < size {
+            let length = SNAPSHOT_CHUNK_SIZE.min(size - offset);
+            let chunk = segment.store.read(offset, length as usize)?;
+            if sender
+                .blocking_send(format!("DATA {}", STANDARD.encode(chunk)))
+                .is_err()
+            {
+                return Ok(());
+            }
+            offset += length;
+        }
+    }
+
+    let _ = sender.blocking_send(format!("END {}", end));
+
+    Ok(())
+}
+
+fn lag(applied: Version, log_position: Version, caught_up_at: Instant) -> Duration {
+    if applied >= log_position {
+        Duration::ZERO
+    } else {
+        caught_up_at.elapsed()
+    }
+}
+
+fn format_entry<T: entry::key::Serializable>(event: &ChangeEvent<T>) -> Result<String> {
+    let header = format!(
+        "ENTRY {} {} {} {}",
+        event.position, event.next_position, event.timestamp, event.continues_group as u8
+    );
+    let key = STANDARD.encode(event.key.serialize()?);
+
+    Ok(match &event.value {
+        Some(value) => format!("{} PUT {} {}", header, key, STANDARD.encode(value)),
+        None => format!("{} DEL {}", header, key),
+    })
+}
+
+fn parse_entry<T: entry::key::Serializable>(message: &str) -> Result<(Entry<T>, Version, bool)> {
+    let parts: Vec<&str> = message.split(' ').collect();
+    let [
+        "ENTRY",
+        _,
+        next_position,
+        timestamp,
+        continues_group,
+        kind,
+        key,
+        rest @ ..,
+    ] = parts.as_slice()
+    else {
+        return Err(invalid_message(message));
+    };
+
+    let next_position = next_position.parse()?;
+    let timestamp = timestamp.parse().map_err(|_| invalid_message(message))?;
+    let key = T::deserialize(decode(key, message)?)?;
+    let entry = match (*kind, rest) {
+        ("PUT", [value]) => {
+            Entry::new_preserving_timestamp(key, decode(value, message)?, timestamp)
+        }
+        ("DEL", []) => Entry::new_deleted_entry_preserving_timestamp(key, timestamp),
+        _ => return Err(invalid_message(message)),
+    };
+
+    Ok((entry, next_position, *continues_group == "1"))
+}
+
+fn decode(text: &str, message: &str) -> Result<Vec<u8>> {
+    STANDARD.decode(text).map_err(|_| invalid_message(message))
+}
+
+fn invalid_message(message: &str) -> BitcaskError {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("unexpected replication message: {}", message.trim()),
+    )
+    .into()
+}
+
+fn read_position(directory: &str) -> Result<Version> {
+    match fs::read_to_string(Path::new(directory).join(POSITION_FILE_NAME)) {
+        Ok(position) => position.trim().parse(),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(Version {
+            file_id: 0,
+            offset: 0,
+        }),
+        Err(error) => Err(error.into()),
+    }
+}
+
+// syncs the store before saving `position`, so that after a crash the saved position is never
+// ahead of what replay finds; the leader would not send the lost entries again.
+async fn save_position<T, V, C>(store: &Arc<KVStore<T, V, C>>, position: Version) -> Result<()>
+where
+    T: entry::key::Serializable + 'static,
+    V: Send + 'static,
+    C: ValueCodec<V> + 'static,
+{
+    let store = store.clone();
+
+    tokio::task::spawn_blocking(move || {
+        store.sync()?;
+        write_position(store.path(), position)
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+fn write_position(directory: &str, position: Version) -> Result<()> {
+    let directory = Path::new(directory);
+    let staged = directory.join(format!("{}.tmp", POSITION_FILE_NAME));
+    fs::write(&staged, position.to_string())?;
+    File::open(&staged)?.sync_all()?;
+    fs::rename(&staged, directory.join(POSITION_FILE_NAME))?;
+    // the rename only survives a crash once the directory entry is on disk too.
+    #[cfg(unix)]
+    File::open(directory)?.sync_all()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::BitcaskError;
+    use crate::kv_store::KVStore;
+    use crate::replication::{
+        Follower, Freshness, POSITION_FILE_NAME, ReplicationLeader, SNAPSHOT_DIRECTORY_NAME,
+        read_position,
+    };
+    use crate::version::Version;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::TcpListener;
+
+    async fn start_leader(store: Arc<KVStore<String>>) -> (String, Arc<ReplicationLeader>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let leader = Arc::new(ReplicationLeader::new());
+
+        tokio::spawn({
+            let leader = leader.clone();
+            async move {
+                loop {
+                    let (socket, peer) = listener.accept().await.unwrap();
+                    let (store, leader) = (store.clone(), leader.clone());
+                    tokio::spawn(async move {
+                        let (reader, mut writer) = socket.into_split();
+                        let mut reader = BufReader::new(reader);
+                        let mut line = String::new();
+                        reader.read_line(&mut line).await.unwrap();
+                        let position = line.trim().strip_prefix("REPLICATE ").unwrap();
+                        let position = position.parse().unwrap();
+                        let _ = leader
+                            .serve(store, position, &mut reader, &mut writer, peer.to_string())
+                            .await;
+                    });
+                }
+            }
+        });
+
+        (address, leader)
+    }
+
+    async fn eventually(mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        while !condition() {
+            assert!(
+                Instant::now() < deadline,
+                "timed out waiting for replication"
+            );
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_follower_applies_leader_log_and_reports_lag() {
+        let (leader_dir, follower_dir) =
+            (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let leader_store = Arc::new(
+            KVStore::<String>::new(leader_dir.path().to_str().unwrap().to_string(), 64).unwrap(),
+        );
+        let follower_store = Arc::new(
+            KVStore::<String>::new(follower_dir.path().to_str().unwrap().to_string(), 64).unwrap(),
+        );
+
+        leader_store.put("key1".to_string(), vec![1; 20]).unwrap();
+        leader_store.put("key2".to_string(), vec![2; 20]).unwrap();
+        leader_store.delete("key1".to_string()).unwrap();
+
+        let (address, leader) = start_leader(leader_store.clone()).await;
+        let follower = Follower::start(follower_store.clone(), address).unwrap();
+
+        let mut transaction = leader_store.transaction();
+        transaction.put("key3".to_string(), vec![3]).unwrap();
+        transaction.put("key4".to_string(), Vec::new()).unwrap();
+        transaction.commit().unwrap();
+
+        eventually(|| follower_store.contains("key4".to_string())).await;
+        assert_eq!(follower_store.get("key1".to_string()).unwrap(), None);
+        assert_eq!(
+            follower_store.get("key2".to_string()).unwrap(),
+            Some(vec![2; 20])
+        );
+        assert_eq!(
+            follower_store.get("key4".to_string()).unwrap(),
+            Some(Vec::new())
+        );
+        assert_eq!(
+            follower_store.stat("key2".to_string()).unwrap().timestamp,
+            leader_store.stat("key2".to_string()).unwrap().timestamp
+        );
+
+        let log_position = leader_store.log_position().unwrap();
+        eventually(|| {
+            let status = follower.status();
+            status.connected && status.leader_position == log_position
+        })
+        .await;
+        let status = follower.status();
+        assert_eq!(status.applied, log_position);
+        assert_eq!(status.lag, Duration::ZERO);
+
+        eventually(|| {
+            let followers = leader.followers(&leader_store).unwrap();
+            followers.len() == 1 && followers[0].acknowledged == log_position
+        })
+        .await;
+        assert_eq!(
+            leader.followers(&leader_store).unwrap()[0].lag,
+            Duration::ZERO
+        );
+
+        drop(follower);
+        eventually(|| leader.followers(&leader_store).unwrap().is_empty()).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_follower_resumes_from_saved_position() {
+        let (leader_dir, follower_dir) =
+            (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let leader_store = Arc::new(
+            KVStore::<String>::new(leader_dir.path().to_str().unwrap().to_string(), 1024).unwrap(),
+        );
+        let follower_store = Arc::new(
+            KVStore::<String>::new(follower_dir.path().to_str().unwrap().to_string(), 1024)
+                .unwrap(),
+        );
+        let (address, _leader) = start_leader(leader_store.clone()).await;
+
+        leader_store.put("key1".to_string(), vec![1]).unwrap();
+        let follower = Follower::start(follower_store.clone(), address.clone()).unwrap();
+        let log_position = leader_store.log_position().unwrap();
+        eventually(|| read_position(follower_store.path()).unwrap() == log_position).await;
+        drop(follower);
+
+        leader_store.put("key2".to_string(), vec![2]).unwrap();
+        let follower = Follower::start(follower_store.clone(), address).unwrap();
+        assert_eq!(follower.status().applied, log_position);
+
+        eventually(|| follower_store.contains("key2".to_string())).await;
+        assert_eq!(
+            follower_store.get("key1".to_string()).unwrap(),
+            Some(vec![1])
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_follower_bootstraps_when_its_position_was_compacted() {
+        let (leader_dir, follower_dir) =
+            (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let leader_store = Arc::new(
+            KVStore::<String>::new(leader_dir.path().to_str().unwrap().to_string(), 64).unwrap(),
+        );
+        let follower_store = Arc::new(
+            KVStore::<String>::new(follower_dir.path().to_str().unwrap().to_string(), 64).unwrap(),
+        );
+
+        leader_store.put("key1".to_string(), vec![1; 20]).unwrap();
+        leader_store.put("key2".to_string(), vec![2; 20]).unwrap();
+        let lost_position = leader_store.stat("key1".to_string()).unwrap().version;
+        leader_store.put("key1".to_string(), vec![3; 20]).unwrap();
+        leader_store.delete("key2".to_string()).unwrap();
+        leader_store.put("key3".to_string(), vec![4; 20]).unwrap();
+        assert!(
+            leader_store
+                .compact()
+                .unwrap()
+                .contains(&lost_position.file_id)
+        );
+
+        follower_store.put("stale".to_string(), vec![0]).unwrap();
+        std::fs::write(
+            follower_dir.path().join(POSITION_FILE_NAME),
+            lost_position.to_string(),
+        )
+        .unwrap();
+
+        let (address, _leader) = start_leader(leader_store.clone()).await;
+        let follower = Follower::start(follower_store.clone(), address).unwrap();
+
+        eventually(|| follower_store.contains("key3".to_string())).await;
+        assert!(!follower_store.contains("stale".to_string()));
+        assert!(!follower_store.contains("key2".to_string()));
+        assert_eq!(
+            follower_store.get("key1".to_string()).unwrap(),
+            Some(vec![3; 20])
+        );
+        assert_eq!(
+            follower_store.stat("key1".to_string()).unwrap().timestamp,
+            leader_store.stat("key1".to_string()).unwrap().timestamp
+        );
+        assert!(!follower_dir.path().join(SNAPSHOT_DIRECTORY_NAME).exists());
+
+        leader_store.put("key4".to_string(), vec![5]).unwrap();
+        eventually(|| follower_store.contains("key4".to_string())).await;
+        let log_position = leader_store.log_position().unwrap();
+        eventually(|| follower.status().applied == log_position).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_wait_until_bounds_staleness() {
+        let (leader_dir, follower_dir) =
+            (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let leader_store = Arc::new(
+            KVStore::<String>::new(leader_dir.path().to_str().unwrap().to_string(), 1024).unwrap(),
+        );
+        let follower_store = Arc::new(
+            KVStore::<String>::new(follower_dir.path().to_str().unwrap().to_string(), 1024)
+                .unwrap(),
+        );
+        let (address, _leader) = start_leader(leader_store.clone()).await;
+        let follower = Follower::start(follower_store.clone(), address).unwrap();
+
+        leader_store.put("key1".to_string(), vec![1]).unwrap();
+        let version = leader_store.stat("key1".to_string()).unwrap().version;
+        follower
+            .wait_until(Freshness::MinPosition(version), Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(
+            follower_store.get("key1".to_string()).unwrap(),
+            Some(vec![1])
+        );
+
+        follower
+            .wait_until(
+                Freshness::MaxStaleness(Duration::from_secs(2)),
+                Duration::from_secs(10),
+            )
+            .await
+            .unwrap();
+        assert!(follower.status().staleness.unwrap() <= Duration::from_secs(2));
+
+        let unreachable = Version {
+            file_id: u64::MAX,
+            offset: 0,
+        };
+        assert!(matches!(
+            follower
+                .wait_until(
+                    Freshness::MinPosition(unreachable),
+                    Duration::from_millis(50)
+                )
+                .await,
+            Err(BitcaskError::Stale(_))
+        ));
+
+        drop(follower);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent = Follower::start(
+            follower_store.clone(),
+            listener.local_addr().unwrap().to_string(),
+        )
+        .unwrap();
+        assert!(matches!(
+            silent
+                .wait_until(
+                    Freshness::MaxStaleness(Duration::from_secs(60)),
+                    Duration::from_millis(50)
+                )
+                .await,
+            Err(BitcaskError::Stale(_))
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_follower_reports_why_replication_stopped() {
+        let follower_dir = tempfile::tempdir().unwrap();
+        let follower_store = Arc::new(
+            KVStore::<String>::new(follower_dir.path().to_str().unwrap().to_string(), 64).unwrap(),
+        );
+        let address = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+
+        let follower = Follower::start(follower_store, address).unwrap();
+
+        eventually(|| follower.status().last_error.is_some()).await;
+        assert!(!follower.status().connected);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_saved_position_is_covered_by_the_replayed_log() {
+        let (leader_dir, follower_dir) =
+            (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let follower_path = follower_dir.path().to_str().unwrap().to_string();
+        let leader_store = Arc::new(
+            KVStore::<String>::new(leader_dir.path().to_str().unwrap().to_string(), 64).unwrap(),
+        );
+        let follower_store = Arc::new(KVStore::<String>::new(follower_path.clone(), 64).unwrap());
+        let (address, _leader) = start_leader(leader_store.clone()).await;
+
+        let follower = Follower::start(follower_store.clone(), address).unwrap();
+        let mut versions = Vec::new();
+        for i in 0..10u8 {
+            let version = leader_store
+                .put_with_version(format!("key{}", i), vec![i; 20])
+                .unwrap();
+            versions.push((format!("key{}", i), version));
+        }
+        let log_position = leader_store.log_position().unwrap();
+        eventually(|| read_position(&follower_path).unwrap() == log_position).await;
+
+        drop(follower);
+        // the aborted task lets go of the store, and with it the directory lock, a bit later.
+        eventually(|| Arc::strong_count(&follower_store) == 1).await;
+        drop(follower_store);
+        let saved = read_position(&follower_path).unwrap();
+        let follower_store = KVStore::<String>::new(follower_path, 64).unwrap();
+
+        // every entry the saved position claims is in the replayed log.
+        for (key, version) in versions {
+            assert!(version < saved);
+            assert!(follower_store.contains(key));
+        }
+        assert!(
+            !follower_dir
+                .path()
+                .join(format!("{}.tmp", POSITION_FILE_NAME))
+                .exists()
+        );
+    }
+}
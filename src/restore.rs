@@ -0,0 +1,259 @@
+use crate::entry::Entry;
+use crate::error::{BitcaskError, Result};
+use crate::options::{DEFAULT_MAX_SEGMENT_SIZE, KVStoreOptions, SyncPolicy};
+use crate::segment::AppendEntryResponse;
+use crate::segments::Segments;
+use crate::version::Version;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// How far `restore` replays the source log.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RestorePoint {
+    /// Every entry written at or before this second since the Unix epoch.
+    Timestamp(u32),
+    /// Every entry up to and including the one at this log position, as handed out by
+    /// `get_with_version`, `stat` and the conditional writes.
+    Version(Version),
+}
+
+impl RestorePoint {
+    // a transaction is kept or left out as a whole, by the time and position of its last entry.
+    fn includes(&self, group: &[(Entry<Vec<u8>>, AppendEntryResponse)]) -> bool {
+        match self {
+            RestorePoint::Timestamp(timestamp) => group
+                .last()
+                .is_some_and(|(entry, _)| entry.timestamp <= *timestamp),
+            RestorePoint::Version(version) => group
+                .last()
+                .is_some_and(|(_, location)| location.version() <= *version),
+        }
+    }
+}
+
+/// Rebuilds the state `source` was in at `point` as a new store in `target`.
+///
+/// `source` can be a backup or the directory of a running store; it is only read and no
+/// lock is taken. Its segments are replayed in log order, leaving out entries past the
+/// point, and the keys that were live then are written to `target` with their original
+/// timestamps. The state can only be rebuilt from entries that compaction has not yet
+/// removed. Returns the number of keys restored.
+pub fn restore(
+    source: impl AsRef<Path>,
+    target: impl AsRef<Path>,
+    point: RestorePoint,
+) -> Result<usize> {
+    let source = directory_name(source.as_ref())?;
+    let target = target.as_ref();
+
+    if target.exists() && KVStoreOptions::contains_segments(target)? {
+        return Err(BitcaskError::AlreadyExists(target.display().to_string()));
+    }
+
+    let source_segments = Segments::open_read_only(source, DEFAULT_MAX_SEGMENT_SIZE)?;
+    let live = replay_until(&source_segments, point)?;
+
+    fs::create_dir_all(target)?;
+    let target_segments = Segments::new(
+        directory_name(target)?,
+        DEFAULT_MAX_SEGMENT_SIZE,
+        SyncPolicy::Never,
+    )?;
+
+    for (key, location) in &live {
+        let entry = source_segments.read::<Vec<u8>>(
+            location.file_id,
+            location.entry_length as usize,
+            location.offset as u64,
+        )?;
+        target_segments.append_preserving_timestamp(
+            key.clone(),
+            entry.value.value,
+            entry.timestamp,
+        )?;
+    }
+
+    if let Some(active_segment) = target_segments.active_segment() {
+        active_segment.store.sync()?;
+    }
+
+    Ok(live.len())
+}
+
+// keys are handled as their serialized bytes, so a store of any key type can be restored.
+fn replay_until(
+    segments: &Segments,
+    point: RestorePoint,
+) -> Result<BTreeMap<Vec<u8>, AppendEntryResponse>> {
+    let sealed_segments = segments.sealed_segments();
+    let newest_file_id = sealed_segments.last().map(|segment| segment.file_id);
+    let mut live = BTreeMap::new();
+
+    for segment in sealed_segments {
+        if let RestorePoint::Version(version) = point
+            && segment.file_id > version.file_id
+        {
+            break;
+        }
+
+        // the newest segment of a running store may end in an append still in progress.
+        let allow_torn_tail = Some(segment.file_id) == newest_file_id;
+        let mut group = Vec::new();
+
+        for (entry, offset, length) in segment.read_from::<Vec<u8>>(0, allow_torn_tail)? {
+            let continues_group = entry.value.continues_group();
+            let location = AppendEntryResponse {
+                file_id: segment.file_id,
+                offset: offset as i64,
+                entry_length: length,
+                value_size: entry.value.value.len() as u32,
+                timestamp: entry.timestamp,
+            };
+            group.push((entry, location));
+
+            if continues_group {
+                continue;
+            }
+
+            let included = point.includes(&group);
+
+            for (entry, location) in group.drain(..) {
+                if !included {
+                    continue;
+                }
+
+                if entry.value.is_tombstone() {
+                    live.remove(&entry.key);
+                } else {
+                    live.insert(entry.key, location);
+                }
+            }
+        }
+    }
+
+    Ok(live)
+}
+
+fn directory_name(directory: &Path) -> Result<String> {
+    directory.to_str().map(str::to_string).ok_or_else(|| {
+        BitcaskError::InvalidOptions(format!("directory {:?} is not valid UTF-8", directory))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::entry::Entry;
+    use crate::kv_store::KVStore;
+    use crate::restore::{RestorePoint, restore};
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_restore_before_bulk_delete() {
+        let dir = tempdir().unwrap();
+        let target = tempdir().unwrap();
+        let kv_store =
+            KVStore::<String>::new(dir.path().to_str().unwrap().to_string(), 64).unwrap();
+
+        for i in 0..5u8 {
+            kv_store.put(format!("key{}", i), vec![i; 10]).unwrap();
+        }
+        let before_delete = kv_store.stat("key4".to_string()).unwrap().timestamp;
+        thread::sleep(Duration::from_millis(1100));
+        for i in 0..5u8 {
+            kv_store.delete(format!("key{}", i)).unwrap();
+        }
+
+        let restored = restore(
+            dir.path(),
+            target.path(),
+            RestorePoint::Timestamp(before_delete),
+        )
+        .unwrap();
+        assert_eq!(restored, 5);
+
+        let restored_store =
+            KVStore::<String>::new(target.path().to_str().unwrap().to_string(), 64).unwrap();
+        for i in 0..5u8 {
+            assert_eq!(
+                restored_store.get(format!("key{}", i)).unwrap(),
+                Some(vec![i; 10])
+            );
+        }
+    }
+
+    #[test]
+    fn test_restore_until_version() {
+        let dir = tempdir().unwrap();
+        let target = tempdir().unwrap();
+        let kv_store =
+            KVStore::<String>::new(dir.path().to_str().unwrap().to_string(), 30).unwrap();
+
+        kv_store.put("key1".to_string(), vec![1]).unwrap();
+        let version = kv_store
+            .put_if_absent("key2".to_string(), vec![2])
+            .unwrap()
+            .unwrap();
+        kv_store.put("key1".to_string(), vec![3]).unwrap();
+
+        let mut transaction = kv_store.transaction();
+        transaction.put("key3".to_string(), vec![4]).unwrap();
+        transaction.delete("key2".to_string());
+        transaction.commit().unwrap();
+
+        restore(dir.path(), target.path(), RestorePoint::Version(version)).unwrap();
+
+        let restored_store =
+            KVStore::<String>::new(target.path().to_str().unwrap().to_string(), 30).unwrap();
+        assert_eq!(
+            restored_store.get("key1".to_string()).unwrap(),
+            Some(vec![1])
+        );
+        assert_eq!(
+            restored_store.get("key2".to_string()).unwrap(),
+            Some(vec![2])
+        );
+        assert_eq!(restored_store.get("key3".to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_restore_keeps_group_by_its_last_entry() {
+        let dir = tempdir().unwrap();
+        let kv_store =
+            KVStore::<String>::new(dir.path().to_str().unwrap().to_string(), 1024).unwrap();
+
+        kv_store
+            .apply_replicated(vec![Entry::new_preserving_timestamp(
+                "key1".to_string(),
+                vec![1],
+                50,
+            )])
+            .unwrap();
+        kv_store
+            .apply_replicated(vec![
+                Entry::new_preserving_timestamp("key1".to_string(), vec![2], 100),
+                Entry::new_preserving_timestamp("key2".to_string(), vec![3], 200),
+            ])
+            .unwrap();
+
+        for (timestamp, key1, key2) in [
+            (150, Some(vec![1]), None),
+            (200, Some(vec![2]), Some(vec![3])),
+        ] {
+            let target = tempdir().unwrap();
+            restore(
+                dir.path(),
+                target.path(),
+                RestorePoint::Timestamp(timestamp),
+            )
+            .unwrap();
+
+            let restored_store =
+                KVStore::<String>::new(target.path().to_str().unwrap().to_string(), 1024).unwrap();
+            assert_eq!(restored_store.get("key1".to_string()).unwrap(), key1);
+            assert_eq!(restored_store.get("key2".to_string()).unwrap(), key2);
+        }
+    }
+}
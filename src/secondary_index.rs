@@ -0,0 +1,112 @@
+use crate::entry;
+use std::collections::{HashMap, HashSet};
+
+pub type IndexExtractor = Box<dyn Fn(&[u8]) -> Vec<Vec<u8>> + Send + Sync>;
+
+pub struct SecondaryIndex<T: entry::key::Serializable> {
+    extractor: IndexExtractor,
+    keys_by_term: HashMap<Vec<u8>, HashSet<T>>,
+    terms_by_key: HashMap<T, Vec<Vec<u8>>>,
+}
+
+impl<T: entry::key::Serializable> SecondaryIndex<T> {
+    pub fn new(extractor: IndexExtractor) -> SecondaryIndex<T> {
+        SecondaryIndex {
+            extractor,
+            keys_by_term: HashMap::new(),
+            terms_by_key: HashMap::new(),
+        }
+    }
+
+    pub fn terms(&self, value: &[u8]) -> Vec<Vec<u8>> {
+        (self.extractor)(value)
+    }
+
+    // replaces whatever terms the key was indexed under before.
+    pub fn put(&mut self, key: T, terms: Vec<Vec<u8>>) {
+        self.remove(&key);
+
+        if terms.is_empty() {
+            return;
+        }
+
+        for term in terms.iter() {
+            self.keys_by_term
+                .entry(term.clone())
+                .or_default()
+                .insert(key.clone());
+        }
+
+        self.terms_by_key.insert(key, terms);
+    }
+
+    pub fn remove(&mut self, key: &T) {
+        let Some(terms) = self.terms_by_key.remove(key) else {
+            return;
+        };
+
+        for term in terms {
+            if let Some(keys) = self.keys_by_term.get_mut(&term) {
+                keys.remove(key);
+
+                if keys.is_empty() {
+                    self.keys_by_term.remove(&term);
+                }
+            }
+        }
+    }
+
+    pub fn lookup(&self, term: &[u8]) -> Vec<T> {
+        self.keys_by_term
+            .get(term)
+            .map(|keys| keys.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn clear(&mut self) {
+        self.keys_by_term.clear();
+        self.terms_by_key.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::secondary_index::SecondaryIndex;
+
+    fn whole_value_index() -> SecondaryIndex<String> {
+        SecondaryIndex::new(Box::new(|value: &[u8]| vec![value.to_vec()]))
+    }
+
+    #[test]
+    fn test_lookup_after_put() {
+        let mut index = whole_value_index();
+
+        let terms = index.terms(b"a@example.com");
+        index.put("user1".to_string(), terms);
+
+        assert_eq!(index.lookup(b"a@example.com"), vec!["user1".to_string()]);
+        assert!(index.lookup(b"b@example.com").is_empty());
+    }
+
+    #[test]
+    fn test_put_replaces_previous_terms() {
+        let mut index = whole_value_index();
+
+        index.put("user1".to_string(), vec![b"old".to_vec()]);
+        index.put("user1".to_string(), vec![b"new".to_vec()]);
+
+        assert!(index.lookup(b"old").is_empty());
+        assert_eq!(index.lookup(b"new"), vec!["user1".to_string()]);
+    }
+
+    #[test]
+    fn test_remove_drops_key_from_all_terms() {
+        let mut index = whole_value_index();
+
+        index.put("user1".to_string(), vec![b"a".to_vec(), b"b".to_vec()]);
+        index.remove(&"user1".to_string());
+
+        assert!(index.lookup(b"a").is_empty());
+        assert!(index.lookup(b"b").is_empty());
+    }
+}
@@ -1,6 +1,9 @@
+use crate::entry;
 use crate::entry::Entry;
 use crate::entry::key::Serializable;
+use crate::error::{BitcaskError, Result};
 use crate::store::Store;
+use crate::version::Version;
 use std::fs::File;
 use std::path::PathBuf;
 
@@ -12,19 +15,34 @@ pub struct Segment {
     pub store: Store,
 }
 
+#[derive(Clone, Copy)]
 pub struct AppendEntryResponse {
     pub file_id: u64,
     pub offset: i64,
     pub entry_length: u32,
+    pub value_size: u32,
+    pub timestamp: u32,
+}
+
+impl AppendEntryResponse {
+    pub fn version(&self) -> Version {
+        Version {
+            file_id: self.file_id,
+            offset: self.offset as u64,
+        }
+    }
 }
 
 impl Segment {
-    pub fn new_segment(file_id: u64, directory: &str) -> Result<Segment, std::io::Error> {
-        let file_name = format!(
+    pub fn file_name(file_id: u64) -> String {
+        format!(
             "{}_{}.{}",
             file_id, SEGMENT_FILE_PREFIX, SEGMENT_FILE_SUFFIX
-        );
-        let file_path = PathBuf::from(directory).join(file_name);
+        )
+    }
+
+    pub fn new_segment(file_id: u64, directory: &str) -> Result<Segment> {
+        let file_path = PathBuf::from(directory).join(Self::file_name(file_id));
         let _ = File::create(&file_path);
 
         let store = Store::new(file_path.to_str().unwrap())?;
@@ -36,15 +54,8 @@ impl Segment {
         })
     }
 
-    pub fn reload_inactive_segment(
-        file_id: u64,
-        directory: &str,
-    ) -> Result<Segment, std::io::Error> {
-        let file_name = format!(
-            "{}_{}.{}",
-            file_id, SEGMENT_FILE_PREFIX, SEGMENT_FILE_SUFFIX
-        );
-        let file_path = PathBuf::from(directory).join(file_name);
+    pub fn reload_inactive_segment(file_id: u64, directory: &str) -> Result<Segment> {
+        let file_path = PathBuf::from(directory).join(Self::file_name(file_id));
 
         let store = Store::reload(file_path.to_str().unwrap())?;
 
@@ -54,34 +65,92 @@ impl Segment {
             store,
         })
     }
-    pub fn append<T: Serializable>(
-        &mut self,
-        mut entry: Entry<T>,
-    ) -> Result<AppendEntryResponse, std::io::Error> {
+    pub fn append<T: Serializable>(&self, mut entry: Entry<T>) -> Result<AppendEntryResponse> {
         let encoded = entry.encode()?;
         let offset = self.store.append(encoded.as_slice())?;
 
         Ok(AppendEntryResponse {
             file_id: self.file_id,
-            offset: offset,
+            offset,
             entry_length: encoded.len() as u32,
+            value_size: entry.value.value.len() as u32,
+            timestamp: entry.timestamp,
         })
     }
 
-    pub fn read<T: Serializable>(
-        &mut self,
-        offset: u64,
-        size: usize,
-    ) -> Result<Entry<T>, std::io::Error> {
+    // writes the entries with a single append so they share one file and can't interleave
+    // with other writers. Every entry but the last is flagged as continuing the group.
+    pub fn append_group<T: Serializable>(
+        &self,
+        entries: Vec<Entry<T>>,
+    ) -> Result<Vec<AppendEntryResponse>> {
+        let last = entries.len().saturating_sub(1);
+        let mut encoded = Vec::new();
+        let mut responses = Vec::with_capacity(entries.len());
+
+        for (position, mut entry) in entries.into_iter().enumerate() {
+            if position < last {
+                entry.continue_group();
+            }
+
+            let bytes = entry.encode()?;
+            responses.push(AppendEntryResponse {
+                file_id: self.file_id,
+                offset: encoded.len() as i64,
+                entry_length: bytes.len() as u32,
+                value_size: entry.value.value.len() as u32,
+                timestamp: entry.timestamp,
+            });
+            encoded.extend_from_slice(&bytes);
+        }
+
+        let start = self.store.append(encoded.as_slice())?;
+
+        for response in &mut responses {
+            response.offset += start;
+        }
+
+        Ok(responses)
+    }
+
+    pub fn read<T: Serializable>(&self, offset: u64, size: usize) -> Result<Entry<T>> {
         let bytes = self.store.read(offset, size)?;
-        Entry::decode(bytes, 0)
+        Entry::decode(bytes, 0).map_err(|_| self.corruption(offset))
     }
 
-    pub fn read_full<T: Serializable>(
-        &mut self,
-    ) -> Result<Vec<(Entry<T>, u32, u32)>, std::io::Error> {
-        let bytes = self.store.read_full()?;
-        Entry::decode_multi(bytes)
+    // returns the entries from `start` to the end of the file with their offset and encoded
+    // length. With `allow_torn_tail` a last entry cut off by the end of the file is left out
+    // instead of reported as corruption, which is how an append still in flight in another
+    // process looks from the outside.
+    pub fn read_from<T: Serializable>(
+        &self,
+        start: u32,
+        allow_torn_tail: bool,
+    ) -> Result<Vec<(Entry<T>, u32, u32)>> {
+        let length = self.store.len()?.saturating_sub(start as u64);
+        let bytes = self.store.read(start as u64, length as usize)?;
+        let mut offset: u32 = 0;
+        let mut entries = Vec::new();
+
+        while (offset as usize) < bytes.len() {
+            if allow_torn_tail && entry::is_truncated(&bytes, offset) {
+                break;
+            }
+
+            let (entry, traversed_offset) = Entry::decode_from(&bytes, offset)
+                .map_err(|_| self.corruption((start + offset) as u64))?;
+            entries.push((entry, start + offset, traversed_offset - offset + 1));
+            offset = traversed_offset + 1;
+        }
+
+        Ok(entries)
+    }
+
+    fn corruption(&self, offset: u64) -> BitcaskError {
+        BitcaskError::Corruption {
+            file_id: self.file_id,
+            offset,
+        }
     }
 }
 
@@ -92,7 +161,8 @@ mod tests {
 
     #[test]
     fn test_new_segment() {
-        let segment = Segment::new_segment(1, ".").unwrap();
+        let dir = tempdir().unwrap();
+        let segment = Segment::new_segment(1, dir.path().to_str().unwrap()).unwrap();
 
         assert_eq!(segment.file_id, 1);
         assert!(segment.file_path.contains("1_segment.data"));
@@ -101,7 +171,7 @@ mod tests {
     #[test]
     fn test_append_and_read_single_entry() {
         let dir = tempdir().unwrap();
-        let mut segment = Segment::new_segment(10, dir.path().to_str().unwrap()).unwrap();
+        let segment = Segment::new_segment(10, dir.path().to_str().unwrap()).unwrap();
 
         let entry = Entry::new("hello".to_string(), vec![1, 2, 3]);
         let response = segment.append(entry).unwrap();
@@ -121,7 +191,7 @@ mod tests {
     #[test]
     fn test_append_and_read_multiple_entries() {
         let dir = tempdir().unwrap();
-        let mut segment = Segment::new_segment(20, dir.path().to_str().unwrap()).unwrap();
+        let segment = Segment::new_segment(20, dir.path().to_str().unwrap()).unwrap();
 
         let entry1 = Entry::new("hello".to_string(), vec![1, 2, 3]);
         let response1 = segment.append(entry1).unwrap();
@@ -147,4 +217,30 @@ mod tests {
         assert_eq!(read_entry2.key, "greet");
         assert_eq!(read_entry2.value.value, "hell world".as_bytes().to_vec());
     }
+
+    #[test]
+    fn test_append_group() {
+        let dir = tempdir().unwrap();
+        let segment = Segment::new_segment(30, dir.path().to_str().unwrap()).unwrap();
+        segment
+            .append(Entry::new("before".to_string(), vec![0]))
+            .unwrap();
+
+        let responses = segment
+            .append_group(vec![
+                Entry::new("a".to_string(), vec![1]),
+                Entry::new_deleted_entry("b".to_string()),
+                Entry::new("c".to_string(), vec![3]),
+            ])
+            .unwrap();
+        let entries = segment.read_from::<String>(0, false).unwrap();
+
+        assert_eq!(entries.len(), 4);
+        for (response, (entry, offset, length)) in responses.iter().zip(&entries[1..]) {
+            assert_eq!(response.offset, *offset as i64);
+            assert_eq!(response.entry_length, *length);
+            assert_eq!(entry.value.continues_group(), entry.key != "c");
+        }
+        assert!(entries[2].0.value.is_tombstone());
+    }
 }
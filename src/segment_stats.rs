@@ -0,0 +1,158 @@
+use crate::segment::AppendEntryResponse;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SegmentStats {
+    pub file_id: u64,
+    pub live_keys: u64,
+    pub live_bytes: u64,
+    pub dead_bytes: u64,
+    pub tombstones: u64,
+}
+
+impl SegmentStats {
+    /// Share of the segment's bytes that no longer back a live key, between 0.0 and 1.0.
+    pub fn fragmentation(&self) -> f64 {
+        let total_bytes = self.live_bytes + self.dead_bytes;
+
+        if total_bytes == 0 {
+            return 0.0;
+        }
+
+        self.dead_bytes as f64 / total_bytes as f64
+    }
+}
+
+// Counters are moved as key directory entries get superseded: the previous location of a
+// key turns dead, the new one is live. Tombstones never back a live key, so they are dead
+// from the moment they are written.
+pub struct SegmentStatsTracker {
+    stats_by_file_id: Mutex<BTreeMap<u64, SegmentStats>>,
+}
+
+impl SegmentStatsTracker {
+    pub fn new() -> SegmentStatsTracker {
+        SegmentStatsTracker {
+            stats_by_file_id: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn record_put(
+        &self,
+        location: &AppendEntryResponse,
+        previous: Option<&AppendEntryResponse>,
+    ) {
+        let mut stats_by_file_id = self.stats_by_file_id.lock().unwrap();
+
+        if let Some(previous) = previous {
+            Self::mark_dead(&mut stats_by_file_id, previous);
+        }
+
+        let stats = Self::stats_for(&mut stats_by_file_id, location.file_id);
+        stats.live_keys += 1;
+        stats.live_bytes += location.entry_length as u64;
+    }
+
+    pub fn record_delete(
+        &self,
+        tombstone: &AppendEntryResponse,
+        previous: Option<&AppendEntryResponse>,
+    ) {
+        let mut stats_by_file_id = self.stats_by_file_id.lock().unwrap();
+
+        if let Some(previous) = previous {
+            Self::mark_dead(&mut stats_by_file_id, previous);
+        }
+
+        let stats = Self::stats_for(&mut stats_by_file_id, tombstone.file_id);
+        stats.tombstones += 1;
+        stats.dead_bytes += tombstone.entry_length as u64;
+    }
+
+    pub fn get(&self, file_id: u64) -> SegmentStats {
+        self.stats_by_file_id
+            .lock()
+            .unwrap()
+            .get(&file_id)
+            .copied()
+            .unwrap_or(SegmentStats {
+                file_id,
+                ..SegmentStats::default()
+            })
+    }
+
+    pub fn remove(&self, file_id: u64) {
+        self.stats_by_file_id.lock().unwrap().remove(&file_id);
+    }
+
+    fn mark_dead(
+        stats_by_file_id: &mut BTreeMap<u64, SegmentStats>,
+        previous: &AppendEntryResponse,
+    ) {
+        let stats = Self::stats_for(stats_by_file_id, previous.file_id);
+        stats.live_keys = stats.live_keys.saturating_sub(1);
+        stats.live_bytes = stats
+            .live_bytes
+            .saturating_sub(previous.entry_length as u64);
+        stats.dead_bytes += previous.entry_length as u64;
+    }
+
+    fn stats_for(
+        stats_by_file_id: &mut BTreeMap<u64, SegmentStats>,
+        file_id: u64,
+    ) -> &mut SegmentStats {
+        stats_by_file_id.entry(file_id).or_insert(SegmentStats {
+            file_id,
+            ..SegmentStats::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::segment::AppendEntryResponse;
+    use crate::segment_stats::SegmentStatsTracker;
+
+    fn location(file_id: u64, entry_length: u32) -> AppendEntryResponse {
+        AppendEntryResponse {
+            file_id,
+            offset: 0,
+            entry_length,
+            value_size: 0,
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn test_overwrite_moves_bytes_from_live_to_dead() {
+        let tracker = SegmentStatsTracker::new();
+
+        tracker.record_put(&location(1, 10), None);
+        tracker.record_put(&location(2, 12), Some(&location(1, 10)));
+
+        let first = tracker.get(1);
+        assert_eq!(first.live_keys, 0);
+        assert_eq!(first.live_bytes, 0);
+        assert_eq!(first.dead_bytes, 10);
+        assert_eq!(first.fragmentation(), 1.0);
+
+        let second = tracker.get(2);
+        assert_eq!(second.live_keys, 1);
+        assert_eq!(second.live_bytes, 12);
+        assert_eq!(second.fragmentation(), 0.0);
+    }
+
+    #[test]
+    fn test_delete_counts_tombstone_as_dead() {
+        let tracker = SegmentStatsTracker::new();
+
+        tracker.record_put(&location(1, 10), None);
+        tracker.record_delete(&location(1, 6), Some(&location(1, 10)));
+
+        let stats = tracker.get(1);
+        assert_eq!(stats.live_keys, 0);
+        assert_eq!(stats.tombstones, 1);
+        assert_eq!(stats.dead_bytes, 16);
+    }
+}
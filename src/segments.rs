@@ -1,123 +1,412 @@
 use crate::entry;
 use crate::entry::Entry;
+use crate::error::{BitcaskError, Result};
+use crate::options::SyncPolicy;
 use crate::segment::{AppendEntryResponse, Segment, SEGMENT_FILE_PREFIX, SEGMENT_FILE_SUFFIX};
 use crate::time_based_id_generator::TimeBasedIdGenerator;
-use std::collections::HashMap;
-use std::io::Error;
-use std::io::ErrorKind::InvalidData;
-use std::{fs, mem};
+use crate::version::Version;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+
+const LOCK_FILE_NAME: &str = "LOCK";
 
 pub struct Segments {
-    pub active_segment: Segment,
-    pub inactive_segments: HashMap<u64, Segment>,
+    // read-only stores have no active segment, every file is opened as an inactive one.
+    pub active_segment: RwLock<Option<Arc<Segment>>>,
+    pub inactive_segments: RwLock<BTreeMap<u64, Arc<Segment>>>,
     pub directory: String,
     pub max_segment_size: u32,
+    pub sync_policy: SyncPolicy,
     pub id_generator: TimeBasedIdGenerator,
+    // appends and rollovers go through this lock one at a time, reads never take it.
+    writer: Mutex<()>,
+    // held for as long as a writable store is open so a second writer can't share the directory.
+    _lock_file: Option<File>,
+    // how many snapshots and iterators still read from each segment; pinned ones are never removed.
+    pins: Arc<Mutex<BTreeMap<u64, usize>>>,
+}
+
+/// Keeps a set of segments from being removed by compaction until it is dropped.
+#[derive(Clone)]
+pub struct SegmentPin {
+    _pinned: Arc<PinnedFileIds>,
+}
+
+struct PinnedFileIds {
+    pins: Arc<Mutex<BTreeMap<u64, usize>>>,
+    file_ids: BTreeSet<u64>,
+}
+
+impl Drop for PinnedFileIds {
+    fn drop(&mut self) {
+        let mut pins = self.pins.lock().unwrap();
+
+        for file_id in &self.file_ids {
+            if let Some(count) = pins.get_mut(file_id) {
+                *count -= 1;
+
+                if *count == 0 {
+                    pins.remove(file_id);
+                }
+            }
+        }
+    }
 }
 
 impl Segments {
-    pub fn new(directory: String, max_segment_size: u32) -> Result<Segments, Error> {
-        let id_generator = TimeBasedIdGenerator::new();
+    pub fn new(
+        directory: String,
+        max_segment_size: u32,
+        sync_policy: SyncPolicy,
+    ) -> Result<Segments> {
+        let lock_file = Self::lock_directory(directory.as_str())?;
+        let inactive_segments = Self::reload(directory.as_str())?;
+        let last_file_id = inactive_segments.keys().next_back().copied().unwrap_or(0);
+
+        let id_generator = TimeBasedIdGenerator::starting_after(last_file_id);
         let segment = Segment::new_segment(id_generator.next(), directory.as_str())?;
 
-        let mut segments = Segments {
-            active_segment: segment,
+        Ok(Segments {
+            active_segment: RwLock::new(Some(Arc::new(segment))),
             id_generator,
             directory,
             max_segment_size,
-            inactive_segments: HashMap::new(),
-        };
+            sync_policy,
+            inactive_segments: RwLock::new(inactive_segments),
+            writer: Mutex::new(()),
+            _lock_file: Some(lock_file),
+            pins: Arc::new(Mutex::new(BTreeMap::new())),
+        })
+    }
 
-        segments.reload().expect("Segments reload failed");
+    pub fn open_read_only(directory: String, max_segment_size: u32) -> Result<Segments> {
+        let inactive_segments = Self::reload(directory.as_str())?;
+        let last_file_id = inactive_segments.keys().next_back().copied().unwrap_or(0);
 
-        Ok(segments)
+        Ok(Segments {
+            active_segment: RwLock::new(None),
+            id_generator: TimeBasedIdGenerator::starting_after(last_file_id),
+            directory,
+            max_segment_size,
+            sync_policy: SyncPolicy::Never,
+            inactive_segments: RwLock::new(inactive_segments),
+            writer: Mutex::new(()),
+            _lock_file: None,
+            pins: Arc::new(Mutex::new(BTreeMap::new())),
+        })
     }
+
+    fn lock_directory(directory: &str) -> Result<File> {
+        let lock_file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(PathBuf::from(directory).join(LOCK_FILE_NAME))?;
+
+        match lock_file.try_lock() {
+            Ok(()) => Ok(lock_file),
+            Err(TryLockError::WouldBlock) => Err(BitcaskError::Locked(directory.to_string())),
+            Err(TryLockError::Error(error)) => Err(error.into()),
+        }
+    }
+
     pub fn append<T: entry::key::Serializable>(
-        &mut self,
+        &self,
         key: T,
         value: Vec<u8>,
-    ) -> Result<AppendEntryResponse, std::io::Error> {
-        self.maybe_roll_over_active_segment()?;
-
-        self.active_segment.append(Entry::new(key, value))
+    ) -> Result<AppendEntryResponse> {
+        self.append_entry(Entry::new(key, value))
     }
 
     pub fn append_delete<T: entry::key::Serializable>(
-        &mut self,
+        &self,
         key: T,
-    ) -> Result<AppendEntryResponse, std::io::Error> {
+    ) -> Result<AppendEntryResponse> {
+        self.append_entry(Entry::new_deleted_entry(key))
+    }
+
+    // used when entries are moved between segments, which must not make them look newer.
+    pub fn append_preserving_timestamp<T: entry::key::Serializable>(
+        &self,
+        key: T,
+        value: Vec<u8>,
+        timestamp: u32,
+    ) -> Result<AppendEntryResponse> {
+        self.append_entry(Entry::new_preserving_timestamp(key, value, timestamp))
+    }
+
+    // appends the entries as one group that replay applies either completely or not at all.
+    pub fn append_group<T: entry::key::Serializable>(
+        &self,
+        entries: Vec<Entry<T>>,
+    ) -> Result<Vec<AppendEntryResponse>> {
+        let _writer = self.writer.lock().unwrap();
         self.maybe_roll_over_active_segment()?;
 
-        self.active_segment.append(Entry::new_deleted_entry(key))
+        let active_segment = self.writable_segment()?;
+        let result = active_segment.append_group(entries)?;
+
+        if self.sync_policy == SyncPolicy::Always {
+            active_segment.store.sync()?;
+        }
+
+        Ok(result)
+    }
+
+    fn append_entry<T: entry::key::Serializable>(
+        &self,
+        entry: Entry<T>,
+    ) -> Result<AppendEntryResponse> {
+        let _writer = self.writer.lock().unwrap();
+        self.maybe_roll_over_active_segment()?;
+
+        let active_segment = self.writable_segment()?;
+        let result = active_segment.append(entry)?;
+
+        if self.sync_policy == SyncPolicy::Always {
+            active_segment.store.sync()?;
+        }
+
+        Ok(result)
     }
 
     pub fn read<T: entry::key::Serializable>(
-        &mut self,
+        &self,
         file_id: u64,
         size: usize,
         offset: u64,
-    ) -> Result<Entry<T>, std::io::Error> {
-        if self.active_segment.file_id == file_id {
-            return self.active_segment.read(offset, size);
-        }
+    ) -> Result<Entry<T>> {
+        self.segment(file_id)?.read(offset, size)
+    }
 
-        if !self.inactive_segments.contains_key(&file_id) {
-            return Err(Error::new(InvalidData, "file_id not found"));
-        }
+    pub fn active_segment(&self) -> Option<Arc<Segment>> {
+        self.active_segment.read().unwrap().clone()
+    }
 
-        let segment = self.inactive_segments.get_mut(&file_id).unwrap();
-        segment.read(offset, size)
+    pub fn is_read_only(&self) -> bool {
+        self.active_segment().is_none()
     }
 
-    pub fn reload(&mut self) -> Result<(), Error> {
-        for entry in fs::read_dir(&self.directory)? {
-            let entry = entry?;
-            let path = entry.path();
-            let file_name = path.file_name().unwrap().to_str().unwrap();
+    // opens segment files that a writer in another process created since the last scan.
+    // Returns how many were added; only meaningful for read-only stores.
+    pub fn refresh(&self) -> Result<usize> {
+        let mut inactive_segments = self.inactive_segments.write().unwrap();
+        let mut added = 0;
 
-            if path.is_file()
-                && file_name
-                    .ends_with(&(SEGMENT_FILE_PREFIX.to_owned() + "." + SEGMENT_FILE_SUFFIX))
+        for entry in fs::read_dir(self.directory.as_str())? {
+            let path = entry?.path();
+
+            if let Some(file_id) = Self::parse_file_id(&path)
+                && path.is_file()
+                && !inactive_segments.contains_key(&file_id)
             {
-                let file_id = String::from(file_name)
-                    .split("_")
-                    .next()
-                    .unwrap()
-                    .parse::<u64>()
-                    .unwrap();
-
-                if self.active_segment.file_id != file_id {
-                    let segment =
-                        Segment::reload_inactive_segment(file_id, self.directory.as_str())?;
-                    self.inactive_segments.insert(file_id, segment);
-                }
+                let segment = Segment::reload_inactive_segment(file_id, self.directory.as_str())?;
+                inactive_segments.insert(file_id, Arc::new(segment));
+                added += 1;
             }
         }
 
+        Ok(added)
+    }
+
+    fn writable_segment(&self) -> Result<Arc<Segment>> {
+        self.active_segment().ok_or(BitcaskError::ReadOnly)
+    }
+
+    pub fn segment(&self, file_id: u64) -> Result<Arc<Segment>> {
+        if let Some(active_segment) = self.active_segment()
+            && active_segment.file_id == file_id
+        {
+            return Ok(active_segment);
+        }
+
+        self.inactive_segments
+            .read()
+            .unwrap()
+            .get(&file_id)
+            .cloned()
+            .ok_or_else(|| BitcaskError::NotFound(format!("segment {}", file_id)))
+    }
+
+    pub fn sealed_segments(&self) -> Vec<Arc<Segment>> {
+        self.inactive_segments
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    pub fn pin(&self, file_ids: BTreeSet<u64>) -> SegmentPin {
+        self.pin_locked(&mut self.pins.lock().unwrap(), file_ids)
+    }
+
+    // rolls the active segment over, unless it is still empty, and pins every sealed segment
+    // without letting go of the writer lock. The segments returned then hold every entry
+    // appended before the call, including entries compaction moved out of a segment it has
+    // removed since, and none of them can be removed until the pin is dropped.
+    // flushes the active segment to disk; sealed ones were synced as they were rolled over.
+    pub fn sync(&self) -> Result<()> {
+        let _writer = self.writer.lock().unwrap();
+
+        if let Some(active_segment) = self.active_segment.read().unwrap().as_ref() {
+            active_segment.store.sync()?;
+        }
+
         Ok(())
     }
 
-    fn maybe_roll_over_segment(&self, segment: &Segment) -> Result<Option<Segment>, Error> {
-        if segment.store.current_write_off_set >= self.max_segment_size as i64 {
-            let new_segment =
-                Segment::new_segment(self.id_generator.next(), self.directory.as_str())?;
+    pub fn seal_and_pin(&self) -> Result<(Vec<Arc<Segment>>, SegmentPin)> {
+        let _writer = self.writer.lock().unwrap();
+
+        if self.writable_segment()?.store.write_off_set() > 0 {
+            self.roll_over_active_segment()?;
+        }
+
+        let mut pins = self.pins.lock().unwrap();
+        let sealed_segments = self.sealed_segments();
+        let pin = self.pin_locked(
+            &mut pins,
+            sealed_segments
+                .iter()
+                .map(|segment| segment.file_id)
+                .collect(),
+        );
+
+        Ok((sealed_segments, pin))
+    }
+
+    fn pin_locked(&self, pins: &mut BTreeMap<u64, usize>, file_ids: BTreeSet<u64>) -> SegmentPin {
+        for file_id in &file_ids {
+            *pins.entry(*file_id).or_insert(0) += 1;
+        }
 
-            return Ok(Some(new_segment));
+        SegmentPin {
+            _pinned: Arc::new(PinnedFileIds {
+                pins: self.pins.clone(),
+                file_ids,
+            }),
         }
+    }
 
-        Ok(None)
+    pub fn is_pinned(&self, file_id: u64) -> bool {
+        self.pins.lock().unwrap().contains_key(&file_id)
     }
 
-    fn maybe_roll_over_active_segment(&mut self) -> Result<(), std::io::Error> {
-        let new_segment = self.maybe_roll_over_segment(&self.active_segment)?;
+    // drops a sealed segment and deletes its file unless it has been pinned in the meantime.
+    // The pins stay locked throughout so a new pin can't slip in before the file is gone.
+    pub fn remove_segment(&self, file_id: u64) -> Result<bool> {
+        let pins = self.pins.lock().unwrap();
 
-        if let Some(segment) = new_segment {
-            let old_segment = mem::replace(&mut self.active_segment, segment);
-            println!("Rolled over  active segment");
-            self.inactive_segments
-                .insert(old_segment.file_id, old_segment);
+        if pins.contains_key(&file_id) {
+            return Ok(false);
         }
 
+        let Some(segment) = self.inactive_segments.write().unwrap().remove(&file_id) else {
+            return Ok(false);
+        };
+        fs::remove_file(&segment.file_path)?;
+
+        Ok(true)
+    }
+
+    // right after the last byte written to the newest segment that holds any, which is where
+    // a change stream that has read everything stands.
+    pub fn end_position(&self) -> Result<Version> {
+        if let Some(active_segment) = self.active_segment()
+            && active_segment.store.write_off_set() > 0
+        {
+            return Ok(Version {
+                file_id: active_segment.file_id,
+                offset: active_segment.store.write_off_set() as u64,
+            });
+        }
+
+        let newest = self
+            .inactive_segments
+            .read()
+            .unwrap()
+            .values()
+            .next_back()
+            .cloned();
+
+        match newest {
+            Some(segment) => Ok(Version {
+                file_id: segment.file_id,
+                offset: segment.store.len()?,
+            }),
+            None => Ok(Version {
+                file_id: 0,
+                offset: 0,
+            }),
+        }
+    }
+
+    pub fn file_ids(&self) -> Vec<u64> {
+        let mut file_ids: Vec<u64> = self
+            .inactive_segments
+            .read()
+            .unwrap()
+            .keys()
+            .copied()
+            .collect();
+
+        if let Some(active_segment) = self.active_segment() {
+            file_ids.push(active_segment.file_id);
+        }
+
+        file_ids
+    }
+
+    fn reload(directory: &str) -> Result<BTreeMap<u64, Arc<Segment>>> {
+        let mut inactive_segments = BTreeMap::new();
+
+        for entry in fs::read_dir(directory)? {
+            let path = entry?.path();
+
+            if let Some(file_id) = Self::parse_file_id(&path)
+                && path.is_file()
+            {
+                let segment = Segment::reload_inactive_segment(file_id, directory)?;
+                inactive_segments.insert(file_id, Arc::new(segment));
+            }
+        }
+
+        Ok(inactive_segments)
+    }
+
+    // segment files are named `<file_id>_segment.data`, anything else in the directory is ignored.
+    fn parse_file_id(path: &std::path::Path) -> Option<u64> {
+        let file_name = path.file_name()?.to_str()?;
+        let suffix = format!("_{}.{}", SEGMENT_FILE_PREFIX, SEGMENT_FILE_SUFFIX);
+
+        file_name.strip_suffix(&suffix)?.parse::<u64>().ok()
+    }
+
+    fn maybe_roll_over_active_segment(&self) -> Result<()> {
+        if self.writable_segment()?.store.write_off_set() >= self.max_segment_size as i64 {
+            self.roll_over_active_segment()?;
+        }
+
+        Ok(())
+    }
+
+    fn roll_over_active_segment(&self) -> Result<()> {
+        let segment = Segment::new_segment(self.id_generator.next(), self.directory.as_str())?;
+        // the inactive map stays locked while the active segment is swapped, so a reader
+        // looking up the old file_id always finds it in one of the two places.
+        let mut inactive_segments = self.inactive_segments.write().unwrap();
+        let old_segment = self
+            .active_segment
+            .write()
+            .unwrap()
+            .replace(Arc::new(segment))
+            .unwrap();
+        old_segment.store.sync()?;
+        inactive_segments.insert(old_segment.file_id, old_segment);
+
         Ok(())
     }
 }
@@ -0,0 +1,86 @@
+use crate::codec::{RawCodec, ValueCodec};
+use crate::entry;
+use crate::error::Result;
+use crate::kv_store_iter::KVStoreIter;
+use crate::segment::AppendEntryResponse;
+use crate::segments::{SegmentPin, Segments};
+use crate::version::Version;
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+
+/// A read-only view of the store frozen at the moment `KVStore::snapshot` was called.
+///
+/// It keeps its own copy of the key directory, so later puts and deletes are invisible to it,
+/// and pins the segments that copy points into so compaction leaves them in place until the
+/// snapshot is dropped.
+pub struct Snapshot<T: entry::key::Serializable, V = Vec<u8>, C: ValueCodec<V> = RawCodec> {
+    segments: Arc<Segments>,
+    entries: BTreeMap<T, AppendEntryResponse>,
+    pin: SegmentPin,
+    codec: PhantomData<fn() -> (V, C)>,
+}
+
+impl<T: entry::key::Serializable, V, C: ValueCodec<V>> Snapshot<T, V, C> {
+    pub(crate) fn new(
+        segments: Arc<Segments>,
+        entries: Vec<(T, AppendEntryResponse)>,
+        pin: SegmentPin,
+    ) -> Snapshot<T, V, C> {
+        Snapshot {
+            segments,
+            entries: entries.into_iter().collect(),
+            pin,
+            codec: PhantomData,
+        }
+    }
+
+    pub fn get(&self, key: T) -> Result<Option<V>> {
+        Ok(self.get_with_version(key)?.map(|(value, _)| value))
+    }
+
+    pub fn get_with_version(&self, key: T) -> Result<Option<(V, Version)>> {
+        let Some(append_entry_response) = self.entries.get(&key) else {
+            return Ok(None);
+        };
+
+        let entry = self.segments.read::<T>(
+            append_entry_response.file_id,
+            append_entry_response.entry_length as usize,
+            append_entry_response.offset as u64,
+        )?;
+
+        Ok(Some((
+            C::decode(entry.value.value)?,
+            append_entry_response.version(),
+        )))
+    }
+
+    pub fn contains(&self, key: T) -> bool {
+        self.entries.contains_key(&key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Every entry of the snapshot in key order.
+    pub fn iter(&self) -> KVStoreIter<T, V, C> {
+        self.range(..)
+    }
+
+    pub fn range(&self, range: impl RangeBounds<T>) -> KVStoreIter<T, V, C> {
+        let entries = self
+            .entries
+            .range(range)
+            .map(|(key, location)| (key.clone(), *location))
+            .collect();
+
+        KVStoreIter::in_given_order(self.segments.clone(), entries, self.pin.clone())
+    }
+}
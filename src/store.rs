@@ -1,19 +1,19 @@
-use std::fs::{File, OpenOptions, remove_file};
+use std::fs::{File, OpenOptions};
 use std::io::ErrorKind::InvalidData;
-use std::io::{Error, Read, Seek, SeekFrom};
-use std::io::{ErrorKind, Write};
+use std::io::{Error, ErrorKind, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI64, Ordering};
 
 pub struct Store {
-    pub writer: Option<File>,
+    pub writer: Option<Mutex<File>>,
     pub reader: File,
-    pub current_write_off_set: i64,
+    pub current_write_off_set: AtomicI64,
     pub path: String,
 }
 
 impl Store {
     pub fn new(filename: &str) -> Result<Self, std::io::Error> {
         let writer = OpenOptions::new()
-            .write(true)
             .append(true)
             .create(true)
             .open(filename)?;
@@ -21,9 +21,9 @@ impl Store {
         let reader = OpenOptions::new().read(true).open(filename)?;
 
         let store = Store {
-            writer: Some(writer),
+            writer: Some(Mutex::new(writer)),
             reader,
-            current_write_off_set: 0,
+            current_write_off_set: AtomicI64::new(0),
             path: filename.to_string(),
         };
 
@@ -36,20 +36,22 @@ impl Store {
         let store = Store {
             writer: None,
             reader,
-            current_write_off_set: 0,
+            current_write_off_set: AtomicI64::new(0),
             path: filename.to_string(),
         };
 
         Ok(store)
     }
-    pub fn append(&mut self, buf: &[u8]) -> Result<i64, Error> {
-        let writer = self
+    pub fn append(&self, buf: &[u8]) -> Result<i64, Error> {
+        let mut writer = self
             .writer
-            .as_mut()
-            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "writing is not allowed"))?;
+            .as_ref()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "writing is not allowed"))?
+            .lock()
+            .unwrap();
 
         let bytes_written = writer.write(buf)?;
-        let current_write_off_set = self.current_write_off_set;
+        let current_write_off_set = self.write_off_set();
 
         if bytes_written < buf.len() {
             return Err(Error::new(
@@ -58,62 +60,91 @@ impl Store {
             ));
         }
 
-        self.current_write_off_set += bytes_written as i64;
+        self.current_write_off_set
+            .fetch_add(bytes_written as i64, Ordering::SeqCst);
         Ok(current_write_off_set)
     }
 
-    pub fn read(&mut self, offset: u64, size: usize) -> Result<Vec<u8>, Error> {
-        self.reader.seek(SeekFrom::Start(offset))?;
+    pub fn write_off_set(&self) -> i64 {
+        self.current_write_off_set.load(Ordering::SeqCst)
+    }
 
+    // reads go through positional I/O so concurrent readers never race on a shared cursor.
+    pub fn read(&self, offset: u64, size: usize) -> Result<Vec<u8>, Error> {
         let mut buf = vec![0; size];
+        let mut bytes_read = 0;
+
+        while bytes_read < size {
+            let read = self.read_at(&mut buf[bytes_read..], offset + bytes_read as u64)?;
 
-        let bytes_read = self.reader.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+
+            bytes_read += read;
+        }
 
         buf.truncate(bytes_read);
 
         Ok(buf)
     }
 
-    pub fn read_full(&mut self) -> Result<Vec<u8>, Error> {
-        let mut buf = vec![];
-
-        self.reader.read_to_end(&mut buf)?;
-
-        Ok(buf)
+    // size of the file on disk, which includes bytes appended by other processes.
+    pub fn len(&self) -> Result<u64, Error> {
+        Ok(self.reader.metadata()?.len())
     }
-    pub fn sync(&mut self) -> Result<(), Error> {
-        let writer = self
+
+    pub fn sync(&self) -> Result<(), Error> {
+        let mut writer = self
             .writer
-            .as_mut()
-            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "writing is not allowed"))?;
+            .as_ref()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "writing is not allowed"))?
+            .lock()
+            .unwrap();
         writer.flush()?;
+        writer.sync_data()?;
         Ok(())
     }
 
-    pub fn remove(&mut self) -> Result<(), Error> {
-        remove_file(&self.path)?;
+    // cuts the file back to `len` bytes. Only for files no writer appends to any more.
+    pub fn truncate(&self, len: u64) -> Result<(), Error> {
+        let file = OpenOptions::new().write(true).open(&self.path)?;
+        file.set_len(len)?;
+        file.sync_all()?;
         Ok(())
     }
+
+    #[cfg(unix)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize, Error> {
+        std::os::unix::fs::FileExt::read_at(&self.reader, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize, Error> {
+        std::os::windows::fs::FileExt::seek_read(&self.reader, buf, offset)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::store::Store;
+    use tempfile::tempdir;
 
     #[test]
     fn test_store_workflow() {
-        let filename = "test_store.dat";
+        let dir = tempdir().unwrap();
+        let filename = dir.path().join("test_store.dat");
 
-        let mut store = Store::new(filename).expect("Failed to create store");
+        let store = Store::new(filename.to_str().unwrap()).expect("Failed to create store");
 
         let data1 = b"hello world";
         let data2 = b"this is ano";
 
-        let size1 = store.append(data1).expect("Failed to append");
-        assert_eq!(size1, data1.len() as i64);
+        let offset1 = store.append(data1).expect("Failed to append");
+        assert_eq!(offset1, 0);
 
-        let size2 = store.append(data2).expect("Failed to append");
-        assert_eq!(size2, data2.len() as i64);
+        let offset2 = store.append(data2).expect("Failed to append");
+        assert_eq!(offset2, data1.len() as i64);
 
         let read_data1 = store.read(0, data1.len()).expect("Failed to read");
         assert_eq!(read_data1, data1);
@@ -123,9 +154,9 @@ mod tests {
             .expect("Failed to read");
         assert_eq!(read_data2, data2);
 
-        let read_full_data = store.read_full().expect("Failed to read");
+        let read_full_data = store
+            .read(0, store.len().unwrap() as usize)
+            .expect("Failed to read");
         assert_eq!(read_full_data, b"hello worldthis is ano");
-
-        store.remove().expect("Failed to remove");
     }
 }
@@ -1,4 +1,5 @@
 use chrono::{DateTime, Utc};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 pub struct SystemClock;
 
@@ -9,16 +10,51 @@ impl SystemClock {
 }
 pub struct TimeBasedIdGenerator {
     clock: SystemClock,
+    last_id: AtomicU64,
 }
 
 impl TimeBasedIdGenerator {
-    pub fn new() -> Self {
+    // ids are never reused, even when several are requested within the same second
+    // or an existing directory already holds ids from the future.
+    pub fn starting_after(last_id: u64) -> Self {
         TimeBasedIdGenerator {
             clock: SystemClock {},
+            last_id: AtomicU64::new(last_id),
         }
     }
 
     pub fn next(&self) -> u64 {
-        self.clock.now().timestamp() as u64
+        let now = self.clock.now().timestamp() as u64;
+        let previous = self
+            .last_id
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+                Some(now.max(last + 1))
+            })
+            .unwrap();
+
+        now.max(previous + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::time_based_id_generator::TimeBasedIdGenerator;
+
+    #[test]
+    fn test_next_is_strictly_increasing() {
+        let id_generator = TimeBasedIdGenerator::starting_after(0);
+
+        let first = id_generator.next();
+        let second = id_generator.next();
+
+        assert!(second > first);
+    }
+
+    #[test]
+    fn test_next_skips_past_seed() {
+        let seed = u64::MAX / 2;
+        let id_generator = TimeBasedIdGenerator::starting_after(seed);
+
+        assert_eq!(id_generator.next(), seed + 1);
     }
 }
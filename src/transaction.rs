@@ -0,0 +1,76 @@
+use crate::codec::{RawCodec, ValueCodec};
+use crate::entry;
+use crate::error::Result;
+use crate::kv_store::KVStore;
+use crate::version::Version;
+use std::collections::BTreeMap;
+
+/// Buffers reads and writes against a store and applies the writes all at once on `commit`.
+///
+/// Every key read through the transaction is remembered with the version it had, or with
+/// `None` if it was missing. `commit` fails with `BitcaskError::Conflict` if any of them has
+/// been written or deleted since; otherwise the writes go to the log as one group that is
+/// either replayed completely after a crash or not at all. Nothing is locked before
+/// `commit`, so a transaction that loses a race is retried by starting a new one.
+///
+/// ```
+/// use bitcask::KVStore;
+///
+/// let dir = tempfile::tempdir().unwrap();
+/// let store = KVStore::<String>::new(dir.path().to_str().unwrap().to_string(), 1024).unwrap();
+/// store.put("from".to_string(), b"10".to_vec()).unwrap();
+///
+/// let mut transaction = store.transaction();
+/// let balance = transaction.get("from".to_string()).unwrap();
+/// assert_eq!(balance, Some(b"10".to_vec()));
+/// transaction.put("from".to_string(), b"0".to_vec()).unwrap();
+/// transaction.put("to".to_string(), b"10".to_vec()).unwrap();
+/// transaction.commit().unwrap();
+/// ```
+pub struct Transaction<'a, T: entry::key::Serializable, V = Vec<u8>, C: ValueCodec<V> = RawCodec> {
+    store: &'a KVStore<T, V, C>,
+    reads: BTreeMap<T, Option<Version>>,
+    // `None` deletes the key.
+    writes: BTreeMap<T, Option<Vec<u8>>>,
+}
+
+impl<'a, T: entry::key::Serializable, V, C: ValueCodec<V>> Transaction<'a, T, V, C> {
+    pub(crate) fn new(store: &'a KVStore<T, V, C>) -> Transaction<'a, T, V, C> {
+        Transaction {
+            store,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// The key's value as this transaction sees it: its own uncommitted write if there is
+    /// one, the store's value otherwise.
+    pub fn get(&mut self, key: T) -> Result<Option<V>> {
+        if let Some(write) = self.writes.get(&key) {
+            return write.clone().map(C::decode).transpose();
+        }
+
+        let current = self.store.get_with_version(key.clone())?;
+        let version = current.as_ref().map(|(_, version)| *version);
+        // a key read twice is validated against the first version seen.
+        self.reads.entry(key).or_insert(version);
+
+        Ok(current.map(|(value, _)| value))
+    }
+
+    pub fn put(&mut self, key: T, value: V) -> Result<()> {
+        self.writes.insert(key, Some(C::encode(value)?));
+
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: T) {
+        self.writes.insert(key, None);
+    }
+
+    /// Checks the keys read and appends the buffered writes. Returns
+    /// `BitcaskError::Conflict` and writes nothing if one of the reads is stale.
+    pub fn commit(self) -> Result<()> {
+        self.store.commit(self.reads, self.writes)
+    }
+}
@@ -1,7 +1,7 @@
 use std::io::Error;
 use std::io::ErrorKind::InvalidData;
 
-pub fn get_int_from_le_bytes(content: &Vec<u8>, offset: u32) -> Result<u32, Error> {
+pub fn get_int_from_le_bytes(content: &[u8], offset: u32) -> Result<u32, Error> {
     let start = offset as usize;
     let end = start + size_of::<u32>();
 
@@ -0,0 +1,53 @@
+use crate::error::BitcaskError;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// Identifies one write of a key: the segment and offset its entry was appended at.
+///
+/// Every put lands at a new position, so a key's version changes whenever it is written and
+/// versions of later writes compare greater. Pass the version read with
+/// `KVStore::get_with_version` back to `compare_and_swap` to make sure nobody wrote the key in
+/// between. Its text form, `<file_id>:<offset>`, is what the server hands out to clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version {
+    pub file_id: u64,
+    pub offset: u64,
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.file_id, self.offset)
+    }
+}
+
+impl FromStr for Version {
+    type Err = BitcaskError;
+
+    fn from_str(version: &str) -> Result<Self, Self::Err> {
+        let invalid = || BitcaskError::InvalidVersion(version.to_string());
+        let (file_id, offset) = version.split_once(':').ok_or_else(invalid)?;
+
+        Ok(Version {
+            file_id: file_id.parse().map_err(|_| invalid())?,
+            offset: offset.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::version::Version;
+
+    #[test]
+    fn test_round_trips_through_text() {
+        let version = Version {
+            file_id: 1700000000,
+            offset: 42,
+        };
+
+        assert_eq!(version.to_string(), "1700000000:42");
+        assert_eq!("1700000000:42".parse::<Version>().unwrap(), version);
+        assert!("1700000000".parse::<Version>().is_err());
+        assert!("a:1".parse::<Version>().is_err());
+    }
+}
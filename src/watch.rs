@@ -0,0 +1,207 @@
+use crate::entry;
+use crate::version::Version;
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::time::Duration;
+
+/// Which keys a watcher is told about.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WatchTarget<T> {
+    Key(T),
+    /// Every key whose serialized form starts with these bytes. With the order-preserving
+    /// tuple encodings, the serialized leading components of a tuple key are such a prefix.
+    Prefix(Vec<u8>),
+}
+
+/// A change delivered to a watcher. Values are the bytes written by the store's codec.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WatchEvent<T> {
+    Put {
+        key: T,
+        value: Vec<u8>,
+        version: Version,
+    },
+    Delete {
+        key: T,
+        version: Version,
+    },
+    /// The watcher's buffer was full and this many events were dropped before the next one.
+    Lagged {
+        missed: u64,
+    },
+}
+
+/// The receiving end of `KVStore::watch`. Dropping it unsubscribes.
+pub struct Watcher<T> {
+    receiver: Receiver<WatchEvent<T>>,
+}
+
+impl<T> Watcher<T> {
+    /// Blocks until the next event. Returns `None` once the store is dropped.
+    pub fn recv(&self) -> Option<WatchEvent<T>> {
+        self.receiver.recv().ok()
+    }
+
+    pub fn try_recv(&self) -> Option<WatchEvent<T>> {
+        self.receiver.try_recv().ok()
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<WatchEvent<T>> {
+        self.receiver.recv_timeout(timeout).ok()
+    }
+}
+
+impl<T> Iterator for Watcher<T> {
+    type Item = WatchEvent<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.recv()
+    }
+}
+
+struct Subscriber<T> {
+    target: WatchTarget<T>,
+    sender: SyncSender<WatchEvent<T>>,
+    missed: u64,
+}
+
+// Writers never wait on a slow watcher: events go out with `try_send`, and whatever doesn't
+// fit is counted and reported as a single `Lagged` event once there is room again.
+pub struct Watchers<T: entry::key::Serializable> {
+    subscribers: Mutex<Vec<Subscriber<T>>>,
+    buffer_size: usize,
+}
+
+impl<T: entry::key::Serializable> Watchers<T> {
+    pub fn new(buffer_size: usize) -> Watchers<T> {
+        Watchers {
+            subscribers: Mutex::new(Vec::new()),
+            buffer_size,
+        }
+    }
+
+    pub fn subscribe(&self, target: WatchTarget<T>) -> Watcher<T> {
+        let (sender, receiver) = mpsc::sync_channel(self.buffer_size);
+
+        self.subscribers.lock().unwrap().push(Subscriber {
+            target,
+            sender,
+            missed: 0,
+        });
+
+        Watcher { receiver }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subscribers.lock().unwrap().is_empty()
+    }
+
+    pub fn notify_put(&self, key: &T, value: &[u8], version: Version) {
+        self.notify(key, || WatchEvent::Put {
+            key: key.clone(),
+            value: value.to_vec(),
+            version,
+        });
+    }
+
+    pub fn notify_delete(&self, key: &T, version: Version) {
+        self.notify(key, || WatchEvent::Delete {
+            key: key.clone(),
+            version,
+        });
+    }
+
+    fn notify(&self, key: &T, event: impl Fn() -> WatchEvent<T>) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+
+        if subscribers.is_empty() {
+            return;
+        }
+
+        let serialized_key = key.serialize().ok();
+
+        subscribers.retain_mut(|subscriber| {
+            let matches = match &subscriber.target {
+                WatchTarget::Key(watched) => watched == key,
+                WatchTarget::Prefix(prefix) => serialized_key
+                    .as_ref()
+                    .is_some_and(|serialized_key| serialized_key.starts_with(prefix)),
+            };
+
+            !matches || subscriber.send(event())
+        });
+    }
+}
+
+impl<T> Subscriber<T> {
+    // returns false once the watcher has been dropped.
+    fn send(&mut self, event: WatchEvent<T>) -> bool {
+        if self.missed > 0 {
+            match self.sender.try_send(WatchEvent::Lagged {
+                missed: self.missed,
+            }) {
+                Ok(()) => self.missed = 0,
+                Err(TrySendError::Full(_)) => {
+                    self.missed += 1;
+                    return true;
+                }
+                Err(TrySendError::Disconnected(_)) => return false,
+            }
+        }
+
+        match self.sender.try_send(event) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.missed += 1;
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::version::Version;
+    use crate::watch::{WatchEvent, WatchTarget, Watchers};
+
+    fn version(offset: u64) -> Version {
+        Version { file_id: 1, offset }
+    }
+
+    #[test]
+    fn test_full_buffer_reports_lag() {
+        let watchers = Watchers::<String>::new(2);
+        let watcher = watchers.subscribe(WatchTarget::Key("key".to_string()));
+
+        for offset in 0..5 {
+            watchers.notify_put(&"key".to_string(), &[offset as u8], version(offset));
+        }
+
+        assert!(matches!(watcher.try_recv(), Some(WatchEvent::Put { .. })));
+        assert!(matches!(watcher.try_recv(), Some(WatchEvent::Put { .. })));
+        assert_eq!(watcher.try_recv(), None);
+
+        watchers.notify_delete(&"key".to_string(), version(5));
+
+        assert_eq!(watcher.try_recv(), Some(WatchEvent::Lagged { missed: 3 }));
+        assert_eq!(
+            watcher.try_recv(),
+            Some(WatchEvent::Delete {
+                key: "key".to_string(),
+                version: version(5)
+            })
+        );
+    }
+
+    #[test]
+    fn test_dropped_watcher_is_unsubscribed() {
+        let watchers = Watchers::<String>::new(2);
+        let watcher = watchers.subscribe(WatchTarget::Prefix(b"user:".to_vec()));
+        drop(watcher);
+
+        watchers.notify_delete(&"user:1".to_string(), version(0));
+
+        assert!(watchers.subscribers.lock().unwrap().is_empty());
+    }
+}